@@ -1,78 +1,431 @@
 use std::io::{self, Write};
-use std::{collections::HashMap, convert::TryFrom, iter};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    fmt, fs, iter, mem,
+    ops::Range,
+    path::PathBuf,
+    ptr,
+    rc::Rc,
+    str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+/// Why evaluating Forth failed.
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum ForthError {
     #[error("Division by zero!")]
     DivisionByZero,
     #[error("Stack underflow!")]
     StackUnderflow,
+    #[error("Return stack underflow!")]
+    ReturnStackUnderflow,
+    #[error("Floating-point stack underflow!")]
+    FloatStackUnderflow,
+    #[error("Stack overflow!")]
+    StackOverflow,
+    #[error("Return stack overflow!")]
+    ReturnStackOverflow,
+    #[error("Floating-point stack overflow!")]
+    FloatStackOverflow,
+    #[error("Call depth exceeded!")]
+    CallDepthExceeded,
+    #[error("Source files nested too deeply!")]
+    IncludeDepthExceeded,
+    #[error("Step limit exceeded!")]
+    StepLimitExceeded,
+    #[error("Data space exhausted!")]
+    DataSpaceExhausted,
+    #[error("Invalid memory address: {0}")]
+    InvalidAddress(i64),
     #[error("Unknown word: {0}")]
     UnknownWord(String),
     #[error("Invalid word: {0}")]
     InvalidWord(String),
+    #[error("String too long: {0} bytes")]
+    StringTooLong(usize),
+    #[error("Cannot read input: {0}")]
+    InputError(String),
+    #[error("End of input")]
+    EndOfInput,
+    #[error("Cannot write output: {0}")]
+    OutputError(String),
+    #[error("Cannot read {0}: {1}")]
+    FileError(String, String),
+    #[error("{location}: {error}")]
+    Included {
+        location: String,
+        error: Box<ForthError>,
+    },
     #[error("Unterminated input")]
     Unterminated,
+    #[error("Control structure mismatch: {0}")]
+    ControlMismatch(String),
+    #[error("Stack effect of {0} does not match its declaration")]
+    StackEffectMismatch(String),
+    #[error("Interpreting a compile-only word: {0}")]
+    CompileOnly(String),
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Timed out")]
+    TimedOut,
     #[error("Bye")]
     UserQuit,
 }
 
+/// Where a word starts in the input, counting lines and columns from 1,
+/// along with its length in characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+// Given to words carried over from an earlier input, which can no longer be
+// pointed at. Real spans start at line 1.
+const NO_SPAN: Span = Span {
+    line: 0,
+    column: 0,
+    len: 0,
+};
+
+// A whitespace delimited word from the input. Parsing words such as `.(`
+// also carry the text they consumed from the input following them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexeme {
+    text: String,
+    span: Span,
+    parsed: Option<Vec<u8>>,
+    // The bytes of the input the lexeme was read from, including any parsed
+    // text.
+    range: Range<usize>,
+}
+
+// Walks the input a character at a time, tracking the current position.
+struct Scanner<'a> {
+    chars: iter::Peekable<str::Chars<'a>>,
+    line: usize,
+    column: usize,
+    offset: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.chars().peekable(),
+            line: 1,
+            column: 1,
+            offset: 0,
+        }
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn next_is_whitespace(&mut self) -> bool {
+        self.chars.peek().is_some_and(|c| c.is_whitespace())
+    }
+
+    // Skip leading whitespace and read the next word.
+    fn word(&mut self) -> Option<Lexeme> {
+        while self.next_is_whitespace() {
+            self.advance();
+        }
+
+        let mut span = Span {
+            line: self.line,
+            column: self.column,
+            len: 0,
+        };
+        let start = self.offset;
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            text.push(c);
+            self.advance();
+        }
+
+        if text.is_empty() {
+            None
+        } else {
+            span.len = text.chars().count();
+            Some(Lexeme {
+                text,
+                span,
+                parsed: None,
+                range: start..self.offset,
+            })
+        }
+    }
+
+    // Read the text up to `delimiter` for a parsing word, consuming the
+    // delimiter. The single space separating the text from the parsing word
+    // is not part of it. Text left open at the end of the input runs to the
+    // end of the input.
+    fn parse(&mut self, delimiter: char) -> String {
+        if self.next_is_whitespace() {
+            self.advance();
+        }
+        let mut text = String::new();
+        while let Some(c) = self.advance() {
+            if c == delimiter {
+                break;
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    // As `parse` with a `"` delimiter, translating the escapes of `S\"`.
+    // An escaped `"` does not end the text. `\x` gives a single byte, so the
+    // text need not be UTF-8.
+    fn parse_escaped(&mut self) -> Vec<u8> {
+        if self.next_is_whitespace() {
+            self.advance();
+        }
+        let mut text = Vec::new();
+        let mut buffer = [0; 4];
+        while let Some(c) = self.advance() {
+            let c = match c {
+                '"' => break,
+                '\\' => match self.advance() {
+                    Some('a') => '\x07',
+                    Some('b') => '\x08',
+                    Some('e') => '\x1b',
+                    Some('f') => '\x0c',
+                    Some('l') | Some('n') => '\n',
+                    Some('m') => {
+                        text.push(b'\r');
+                        '\n'
+                    }
+                    Some('q') => '"',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('v') => '\x0b',
+                    Some('z') => '\0',
+                    Some('x') => {
+                        let digits: String = (0..2).filter_map(|_| self.advance()).collect();
+                        match u8::from_str_radix(&digits, 16) {
+                            Ok(byte) => text.push(byte),
+                            Err(_) => text.extend_from_slice(digits.as_bytes()),
+                        }
+                        continue;
+                    }
+                    Some(c) => c,
+                    None => break,
+                },
+                c => c,
+            };
+            text.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+        }
+        text
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Number(f64),
+    Number(i64),
+    // A double-cell number, pushed as two cells with the high cell on top.
+    Double(i128),
+    Float(f64),
+    // A VALUE, fetching the cell at the given data space address.
+    Value(usize),
     Builtin(ForthBuiltin),
-    Word(String),
-    Definition(Vec<Token>),
-    UserDefined(Vec<Token>),
+    Word(String, Span),
+    Definition(Rc<Entry>),
+    Host(Rc<HostWord>),
+    // The lexemes of a colon definition, compiled into the dictionary when
+    // the interpreter reaches them.
+    UserDefined(Vec<Lexeme>),
+    // Give the most recent definition the given runtime behavior and return
+    // from the current definition.
+    Does(Rc<[Token]>),
+    // Jump to the given position within the current definition.
+    Branch(usize),
+    // Pop a flag and jump to the given position if it is zero.
+    BranchIfZero(usize),
+    // Print the given text.
+    Print(String),
+    // A string literal, copied into data space when compiled. Pushes the
+    // address and length of the copy.
+    String(Vec<u8>),
+    // As `String`, but stored as a counted string and pushing its address.
+    CountedString(Vec<u8>),
+    // A string in data space, pushing its address and length.
+    StringAt(usize, usize),
+    // Move the loop limit and index to the return stack.
+    Do,
+    // As `Do`, but jump to the given position when limit and index match.
+    QuestionDo(usize),
+    // Step the loop index by one, jumping back to the loop body until done.
+    Loop(usize),
+    // Step the loop index by a popped amount, jumping back until done.
+    PlusLoop(usize),
+    // Discard the loop parameters and jump past the end of the loop.
+    Leave(usize),
 }
 
 impl Token {
-    pub fn eval(&self, state: &mut State) -> Result<Option<f64>, ForthError> {
+    pub fn eval(&self, state: &mut State) -> Result<Option<i64>, ForthError> {
         let result = match self {
             Token::Number(num) => Some(*num),
+            Token::Double(num) => {
+                state.push_double(*num);
+                None
+            }
+            Token::Float(num) => {
+                state.fpush(*num);
+                None
+            }
+            Token::Value(address) => {
+                let x = state.fetch(*address as i64)?;
+                state.push(x);
+                None
+            }
             Token::Builtin(builtin) => builtin.eval(state)?,
-            Token::Word(word) => self.eval_word(state, word)?,
-            Token::UserDefined(user_defined_tokens) => {
-                self.eval_user_defined(state, user_defined_tokens)?
+            Token::Host(word) => {
+                word.call(state)?;
+                None
+            }
+            Token::Word(word, span) => self
+                .eval_word(state, word)
+                .inspect_err(|_| state.mark_error(*span))?,
+            Token::UserDefined(lexemes) => {
+                let tokens = state.compile_definition(lexemes)?;
+                self.eval_user_defined(state, &tokens)?
+            }
+            Token::Definition(entry) => {
+                if let Some(address) = entry.data {
+                    state.push(address as i64);
+                }
+                state.enter()?;
+                state.push_tokens(entry.behavior.clone());
+                let result = self.eval_definition(state);
+                state.pop_tokens();
+                result?
+            }
+            Token::Does(behavior) => {
+                state.set_behavior(behavior.clone())?;
+                state.exit();
+                None
+            }
+            Token::Print(text) => {
+                state.print(format_args!("{}", text))?;
+                None
+            }
+            Token::String(text) => {
+                let address = state.place_string(text)?;
+                state.push(address as i64);
+                state.push(text.len() as i64);
+                None
+            }
+            Token::CountedString(text) => {
+                let address = state.place_counted_string(text)?;
+                Some(address as i64)
+            }
+            Token::StringAt(address, len) => {
+                state.push(*address as i64);
+                state.push(*len as i64);
+                None
+            }
+            Token::Branch(target) => {
+                state.jump(*target);
+                None
+            }
+            Token::BranchIfZero(target) => {
+                if state.pop()? == 0 {
+                    state.jump(*target);
+                }
+                None
+            }
+            Token::Do => {
+                // (limit index -- )
+                let (index, limit) = state.pop2()?;
+                state.rpush(limit);
+                state.rpush(index);
+                None
+            }
+            Token::QuestionDo(target) => {
+                // (limit index -- )
+                let (index, limit) = state.pop2()?;
+                if index == limit {
+                    state.jump(*target);
+                } else {
+                    state.rpush(limit);
+                    state.rpush(index);
+                }
+                None
+            }
+            Token::Loop(target) => {
+                if !state.step_loop(1)? {
+                    state.jump(*target);
+                }
+                None
             }
-            Token::Definition(user_defined_tokens) => {
-                state.push_tokens(user_defined_tokens);
-                self.eval_definition(state)?
+            Token::PlusLoop(target) => {
+                // (n -- )
+                let increment = state.pop()?;
+                if !state.step_loop(increment)? {
+                    state.jump(*target);
+                }
+                None
+            }
+            Token::Leave(target) => {
+                state.rpop2()?;
+                state.jump(*target);
+                None
             }
         };
         Ok(result)
     }
 
-    fn eval_word(&self, state: &mut State, word: &str) -> Result<Option<f64>, ForthError> {
+    fn eval_word(&self, state: &mut State, word: &str) -> Result<Option<i64>, ForthError> {
         match state.lookup(word) {
-            Some(Token::Number(value)) => Ok(Some(value)),
-            Some(Token::Definition(user_defined_tokens)) => {
-                state.push_tokens(user_defined_tokens.as_slice());
-                let result = self.eval_definition(state);
-                state.pop_tokens();
-                result
-            }
-            Some(stored_token) => Err(ForthError::InvalidWord(format!("{:?}", stored_token))),
+            Some(entry) => Token::Definition(entry).eval(state),
             None => {
-                let parsed = self.parse_word(word.as_ref())?;
+                let parsed = self.parse_word(state, word.as_ref())?;
                 parsed.eval(state)
             }
         }
     }
 
-    fn parse_word(&self, word: &str) -> Result<Token, ForthError> {
-        if let Ok(builtin) = ForthBuiltin::try_from(word.to_lowercase().as_ref()) {
+    fn parse_word(&self, state: &State, word: &str) -> Result<Token, ForthError> {
+        let lowercase = word.to_lowercase();
+        if let Some(host) = state.host_words.get(&lowercase) {
+            Ok(Token::Host(host.clone()))
+        } else if let Ok(builtin) = ForthBuiltin::try_from(lowercase.as_ref()) {
             Ok(Token::Builtin(builtin))
+        } else if CONTROL_WORDS.contains(&lowercase.as_ref()) {
+            Err(ForthError::CompileOnly(word.to_string()))
         } else {
             Err(ForthError::UnknownWord(word.to_string()))
         }
     }
 
-    fn eval_definition(&self, state: &mut State) -> Result<Option<f64>, ForthError> {
+    fn eval_definition(&self, state: &mut State) -> Result<Option<i64>, ForthError> {
         while let Some(token) = state.next_token() {
+            state.step()?;
             if let Some(value) = token.eval(state)? {
                 state.push(value);
             }
+            state.check_limits()?;
         }
         Ok(None)
     }
@@ -81,15 +434,187 @@ impl Token {
         &self,
         state: &mut State,
         tokens: &[Token],
-    ) -> Result<Option<f64>, ForthError> {
+    ) -> Result<Option<i64>, ForthError> {
         match tokens {
-            [Token::Word(name), rest @ ..] => {
-                state.define_word(name.clone(), Token::Definition(rest.to_vec()));
+            [Token::Word(name, _), rest @ ..] => {
+                let body = self.resolve_definition(state, rest)?;
+                state.define_word(name.clone(), Entry::colon(body));
                 Ok(None)
             }
             _ => Err(ForthError::InvalidWord(format!("{:?}", tokens))),
         }
     }
+
+    // Bind every word in a definition body to its current meaning so later
+    // redefinitions do not change the behavior of this definition.
+    fn resolve_definition(
+        &self,
+        state: &mut State,
+        tokens: &[Token],
+    ) -> Result<Vec<Token>, ForthError> {
+        let mut resolved = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.iter();
+
+        while let Some(token) = tokens.next() {
+            let token = match token {
+                Token::Word(word, _) if word.eq_ignore_ascii_case("to") => {
+                    // TO takes its name when compiled, becoming a store into
+                    // the value's data space.
+                    let address = match tokens.next() {
+                        Some(Token::Word(name, span)) => {
+                            match state.lookup(name).and_then(|entry| entry.value_address()) {
+                                Some(address) => address,
+                                None => {
+                                    state.mark_error(*span);
+                                    return Err(ForthError::InvalidWord(name.clone()));
+                                }
+                            }
+                        }
+                        Some(token) => return Err(ForthError::InvalidWord(format!("{:?}", token))),
+                        None => return Err(ForthError::Unterminated),
+                    };
+                    resolved.push(Token::Number(address as i64));
+                    Token::Builtin(ForthBuiltin::Store)
+                }
+                Token::Word(word, span) => match state.lookup(word) {
+                    Some(entry) => Token::Definition(entry),
+                    None => self
+                        .parse_word(state, word)
+                        .inspect_err(|_| state.mark_error(*span))?,
+                },
+                Token::Does(behavior) => {
+                    Token::Does(self.resolve_definition(state, behavior)?.into())
+                }
+                Token::String(text) => Token::StringAt(state.place_string(text)?, text.len()),
+                Token::CountedString(text) => {
+                    Token::Number(state.place_counted_string(text)? as i64)
+                }
+                _ => token.clone(),
+            };
+            resolved.push(token);
+        }
+
+        Ok(resolved)
+    }
+}
+
+// The current input lives above data space, from this address. It starts
+// with the >IN cell, followed by the text returned by SOURCE.
+const INPUT_BUFFER: i64 = 1 << 48;
+
+// Words which parse a string literal from the input, up to a closing `"`.
+const STRING_WORDS: &[&str] = &[".\"", "s\"", "s\\\"", "c\""];
+
+// Words which only have meaning inside a colon definition.
+const CONTROL_WORDS: &[&str] = &[
+    "if", "else", "then", "do", "?do", "loop", "+loop", "leave", "begin", "until", "while",
+    "repeat", "again", "does>",
+];
+
+// A dictionary entry. Executing the word pushes the address of its data
+// field, if it has one, and then runs its behavior.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    data: Option<usize>,
+    behavior: Rc<[Token]>,
+}
+
+impl Entry {
+    fn colon(body: Vec<Token>) -> Self {
+        Self {
+            data: None,
+            behavior: body.into(),
+        }
+    }
+
+    fn created(address: usize) -> Self {
+        Self {
+            data: Some(address),
+            behavior: Rc::new([]),
+        }
+    }
+
+    // The address TO stores into, if this word is a VALUE.
+    fn value_address(&self) -> Option<usize> {
+        match *self.behavior {
+            [Token::Value(address)] => Some(address),
+            _ => None,
+        }
+    }
+}
+
+/// The number of cells a host word takes from and leaves on the data
+/// stack, written `( inputs -- outputs )` in Forth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+type HostFunction = dyn FnMut(&mut State) -> Result<(), ForthError>;
+
+// A word implemented by the host program. Its stack effect, if declared, is
+// checked around every call.
+pub struct HostWord {
+    name: String,
+    effect: Option<StackEffect>,
+    function: RefCell<Box<HostFunction>>,
+}
+
+impl HostWord {
+    fn call(&self, state: &mut State) -> Result<(), ForthError> {
+        let depth = state.stack.len();
+        if let Some(effect) = self.effect {
+            if depth < effect.inputs {
+                return Err(ForthError::StackUnderflow);
+            }
+        }
+        (self.function.borrow_mut())(state)?;
+        match self.effect {
+            Some(effect) if state.stack.len() + effect.inputs != depth + effect.outputs => {
+                Err(ForthError::StackEffectMismatch(self.name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for HostWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("HostWord").field(&self.name).finish()
+    }
+}
+
+// Host words are only equal to themselves.
+impl PartialEq for HostWord {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+// Unresolved control flow while compiling a definition. Each entry holds
+// the position of the branch waiting for its target. A `Do` holds the start
+// of the loop body along with every branch which exits the loop, a `Begin`
+// holds the target of the backward branch closing the loop.
+#[derive(Debug)]
+enum Control {
+    If(usize),
+    Else(usize),
+    Do { start: usize, leaves: Vec<usize> },
+    Begin(usize),
+    While(usize),
+}
+
+// Point the forward branch at `origin` to the end of `tokens`.
+fn resolve_branch(tokens: &mut [Token], origin: usize) {
+    let target = tokens.len();
+    match &mut tokens[origin] {
+        Token::Branch(destination)
+        | Token::BranchIfZero(destination)
+        | Token::QuestionDo(destination)
+        | Token::Leave(destination) => *destination = target,
+        token => unreachable!("not a branch: {:?}", token),
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -98,6 +623,15 @@ pub enum ForthBuiltin {
     Subtract, // -
     Multiply, // *
     Divide,   // /
+    Equal,    // =
+    NotEqual, // <>
+    Less,     // <
+    Greater,  // >
+    ZeroEqual,
+    ZeroLess,
+    ZeroGreater,
+    Abs,
+    And,
     Bye,
     CR,
     Display,
@@ -106,72 +640,257 @@ pub enum ForthBuiltin {
     TwoDrop,
     TwoDup,
     Emit,
+    Exit,
+    I,
+    Invert,
+    J,
+    LShift,
+    Max,
+    Min,
     Mod,
     SlashMod,
+    Negate,
+    Or,
     Over,
     TwoOver,
+    RDrop,
+    RFetch,
+    TwoRFetch,
+    RFrom,
+    TwoRFrom,
     Rot,
+    RShift,
     Show,
-    ShowString,
+    ShowReturn,
     Space,
     Spaces,
     Swap,
     TwoSwap,
+    ToR,
+    TwoToR,
+    Unloop,
+    Xor,
+    // Data space
+    Allot,
+    CComma, // C,
+    CFetch, // C@
+    CStore, // C!
+    CellPlus,
+    Cells,
+    CharPlus,
+    Chars,
+    Comma, // ,
+    Fetch, // @
+    Here,
+    PlusStore, // +!
+    Store,     // !
+    // Strings
+    Count,
+    Type,
+    // Command line arguments
+    Arg,
+    Argc,
+    // Input
+    Accept,
+    Key,
+    KeyQuestion, // KEY?
+    Refill,
+    Source,
+    ToIn, // >IN
+    // Source files
+    Include,
+    Included,
+    Require,
+    Required,
+    // Defining words
+    Constant,
+    Create,
+    To,
+    Value,
+    Variable,
+    // Double cells
+    DAdd,      // D+
+    DSubtract, // D-
+    DLess,     // D<
+    DEqual,    // D=
+    DZeroEqual,
+    DAbs,
+    DDisplay,
+    DNegate,
+    DToS,
+    StoD,
+    MStar,
+    UMStar,
+    UMSlashMod,
+    SMSlashRem,
+    FMSlashMod,
+    StarSlash,
+    StarSlashMod,
+    // Floating point
+    FAdd,      // F+
+    FSubtract, // F-
+    FMultiply, // F*
+    FDivide,   // F/
+    FLess,     // F<
+    FZeroEqual,
+    FZeroLess,
+    FAbs,
+    FCos,
+    FDisplay,
+    FDrop,
+    FDup,
+    FExp,
+    FFetch,
+    FLn,
+    FNegate,
+    FOver,
+    FRot,
+    FShow,
+    FSin,
+    FSqrt,
+    FStore,
+    FSwap,
+    FToS,
+    FloatPlus,
+    Floats,
+    SToF,
 }
 
 impl ForthBuiltin {
-    pub fn eval(&self, state: &mut State) -> Result<Option<f64>, ForthError> {
+    pub fn eval(&self, state: &mut State) -> Result<Option<i64>, ForthError> {
         match self {
             // (n1 n2 -- sum)
             Self::Add => {
                 let (n2, n1) = state.pop2()?;
-                state.push(n1 + n2);
+                state.push(n1.wrapping_add(n2));
             }
             Self::Subtract => {
                 // (n1 n2 -- difference)
                 let (n2, n1) = state.pop2()?;
-                state.push(n1 - n2);
+                state.push(n1.wrapping_sub(n2));
             }
             Self::Multiply => {
                 // (n1 n2 -- result)
                 let (n2, n1) = state.pop2()?;
-                state.push(n1 * n2);
+                state.push(n1.wrapping_mul(n2));
             }
             Self::Divide => {
                 // (n1 n2 -- result)
                 let (n2, n1) = state.pop2()?;
-                if n2 == 0.0 {
-                    return Err(ForthError::DivisionByZero);
-                }
-                state.push(n1 / n2);
+                let (_, quot) = floored_div_mod(n1.into(), n2.into())?;
+                state.push(quot as i64);
+            }
+            Self::Equal => {
+                // (n1 n2 -- flag)
+                let (n2, n1) = state.pop2()?;
+                state.push(flag(n1 == n2));
+            }
+            Self::NotEqual => {
+                // (n1 n2 -- flag)
+                let (n2, n1) = state.pop2()?;
+                state.push(flag(n1 != n2));
+            }
+            Self::Less => {
+                // (n1 n2 -- flag)
+                let (n2, n1) = state.pop2()?;
+                state.push(flag(n1 < n2));
+            }
+            Self::Greater => {
+                // (n1 n2 -- flag)
+                let (n2, n1) = state.pop2()?;
+                state.push(flag(n1 > n2));
+            }
+            Self::ZeroEqual => {
+                // (n -- flag)
+                let n = state.pop()?;
+                state.push(flag(n == 0));
+            }
+            Self::ZeroLess => {
+                // (n -- flag)
+                let n = state.pop()?;
+                state.push(flag(n < 0));
+            }
+            Self::ZeroGreater => {
+                // (n -- flag)
+                let n = state.pop()?;
+                state.push(flag(n > 0));
+            }
+            Self::Abs => {
+                // (n -- u)
+                let n = state.pop()?;
+                state.push(n.wrapping_abs());
+            }
+            Self::Negate => {
+                // (n -- -n)
+                let n = state.pop()?;
+                state.push(n.wrapping_neg());
+            }
+            Self::Min => {
+                // (n1 n2 -- n)
+                let (n2, n1) = state.pop2()?;
+                state.push(n1.min(n2));
+            }
+            Self::Max => {
+                // (n1 n2 -- n)
+                let (n2, n1) = state.pop2()?;
+                state.push(n1.max(n2));
+            }
+            Self::And => {
+                // (x1 x2 -- x)
+                let (x2, x1) = state.pop2()?;
+                state.push(x1 & x2);
+            }
+            Self::Or => {
+                // (x1 x2 -- x)
+                let (x2, x1) = state.pop2()?;
+                state.push(x1 | x2);
+            }
+            Self::Xor => {
+                // (x1 x2 -- x)
+                let (x2, x1) = state.pop2()?;
+                state.push(x1 ^ x2);
+            }
+            Self::Invert => {
+                // (x -- x)
+                let x = state.pop()?;
+                state.push(!x);
+            }
+            Self::LShift => {
+                // (x u -- x)
+                let (u, x) = state.pop2()?;
+                let shifted = x.checked_shl(shift_amount(u)).unwrap_or(0);
+                state.push(shifted);
+            }
+            Self::RShift => {
+                // (x u -- x)
+                // A logical shift: zeroes are shifted into the high bits.
+                let (u, x) = state.pop2()?;
+                let shifted = (x as u64).checked_shr(shift_amount(u)).unwrap_or(0);
+                state.push(shifted as i64);
             }
             Self::Mod => {
                 // (n1 n2 -- rem)
                 let (n2, n1) = state.pop2()?;
-                if n2 == 0.0 {
-                    return Err(ForthError::DivisionByZero);
-                }
-                state.push(n1 % n2);
+                let (rem, _) = floored_div_mod(n1.into(), n2.into())?;
+                state.push(rem as i64);
             }
             Self::SlashMod => {
                 // (n1 n2 -- rem quot)
                 let (n2, n1) = state.pop2()?;
-                if n2 == 0.0 {
-                    return Err(ForthError::DivisionByZero);
-                }
-                state.push(n1 % n2);
-                state.push(n1 / n2);
+                let (rem, quot) = floored_div_mod(n1.into(), n2.into())?;
+                state.push(rem as i64);
+                state.push(quot as i64);
             }
             Self::Bye => {
                 return Err(ForthError::UserQuit);
             }
             Self::CR => {
-                println!();
+                state.print(format_args!("\n"))?;
             }
             Self::Display => {
                 // (n1 -- )
                 let value = state.pop()?;
-                print!("{}", value);
+                state.print(format_args!("{} ", value))?;
             }
             Self::Drop => {
                 // (n1 n2 -- n1)
@@ -199,7 +918,25 @@ impl ForthBuiltin {
             Self::Emit => {
                 // (n1 -- )
                 let value = state.pop()?;
-                print!("{}", value as u8 as char);
+                let c = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                state.print(format_args!("{}", c))?;
+            }
+            Self::Exit => {
+                state.exit();
+            }
+            Self::I => {
+                // ( -- n)
+                let index = state.rpick(0)?;
+                state.push(index);
+            }
+            Self::J => {
+                // ( -- n)
+                // The outer loop's index sits beneath the inner loop's limit.
+                let index = state.rpick(2)?;
+                state.push(index);
             }
             Self::Over => {
                 // (n1 n2 -- n1 n2 n1)
@@ -220,6 +957,32 @@ impl ForthBuiltin {
                 state.push(num1);
                 state.push(num2);
             }
+            Self::RDrop => {
+                // (R: n -- )
+                state.rpop()?;
+            }
+            Self::RFetch => {
+                // ( -- n) (R: n -- n)
+                let value = state.rpick(0)?;
+                state.push(value);
+            }
+            Self::TwoRFetch => {
+                // ( -- n1 n2) (R: n1 n2 -- n1 n2)
+                let (n2, n1) = (state.rpick(0)?, state.rpick(1)?);
+                state.push(n1);
+                state.push(n2);
+            }
+            Self::RFrom => {
+                // ( -- n) (R: n -- )
+                let value = state.rpop()?;
+                state.push(value);
+            }
+            Self::TwoRFrom => {
+                // ( -- n1 n2) (R: n1 n2 -- )
+                let (n2, n1) = state.rpop2()?;
+                state.push(n1);
+                state.push(n2);
+            }
             Self::Rot => {
                 // (n1 n2 n3 -- n2 n3 n1)
                 let (num3, num2) = state.pop2()?;
@@ -229,36 +992,26 @@ impl ForthBuiltin {
                 state.push(num1);
             }
             Self::Show => {
-                state.show_stack();
+                state.show_stack()?;
             }
-            Self::ShowString => {
-                while let Some(next) = state.next_token() {
-                    match next {
-                        Token::Word(word) if word == "\"" => {
-                            break;
-                        }
-                        Token::Word(word) => {
-                            print!("{} ", word);
-                        }
-                        _ => {
-                            return Err(ForthError::Unterminated);
-                        }
-                    }
-                }
+            Self::ShowReturn => {
+                state.show_return_stack()?;
             }
             Self::Space => {
-                print!(" ");
+                state.print(format_args!(" "))?;
             }
             Self::Spaces => {
                 // (n1 -- )
-                let num = state.pop()?;
-                print!(
-                    "{}",
-                    iter::repeat(" ")
-                        .take(num as usize)
-                        .intersperse("")
-                        .collect::<String>()
-                );
+                let mut remaining = usize::try_from(state.pop()?).unwrap_or(0);
+                // Print a chunk at a time, so a huge count can't exhaust memory.
+                // Each chunk is a step, so limits and interrupts still apply.
+                const CHUNK: &str = "                                ";
+                while remaining > 0 {
+                    state.step()?;
+                    let len = remaining.min(CHUNK.len());
+                    state.print(format_args!("{}", &CHUNK[..len]))?;
+                    remaining -= len;
+                }
             }
             Self::Swap => {
                 // (n1 n2 -- n2 n1)
@@ -276,614 +1029,4527 @@ impl ForthBuiltin {
                 state.push(n1);
                 state.push(n2);
             }
-        }
-
-        Ok(None)
-    }
-}
-
-impl TryFrom<&str> for ForthBuiltin {
-    type Error = ForthError;
-
-    fn try_from(input: &str) -> Result<ForthBuiltin, Self::Error> {
-        let builtin = match input {
-            "." => ForthBuiltin::Display,
-            "+" => ForthBuiltin::Add,
-            "-" => ForthBuiltin::Subtract,
-            "*" => ForthBuiltin::Multiply,
-            "/" => ForthBuiltin::Divide,
-            ".\"" => ForthBuiltin::ShowString,
-            "bye" | "quit" => ForthBuiltin::Bye,
-            "cr" => ForthBuiltin::CR,
-            "dup" => ForthBuiltin::Dup,
-            "2dup" => ForthBuiltin::TwoDup,
-            "drop" => ForthBuiltin::Drop,
-            "2drop" => ForthBuiltin::TwoDrop,
-            "emit" => ForthBuiltin::Emit,
-            "/mod" => ForthBuiltin::SlashMod,
-            "mod" => ForthBuiltin::Mod,
-            "over" => ForthBuiltin::Over,
-            "2over" => ForthBuiltin::TwoOver,
-            "rot" => ForthBuiltin::Rot,
-            ".s" => ForthBuiltin::Show,
-            "space" => ForthBuiltin::Space,
-            "spaces" => ForthBuiltin::Spaces,
-            "swap" => ForthBuiltin::Swap,
-            "2swap" => ForthBuiltin::TwoSwap,
-            _ => {
-                return Err(ForthError::UnknownWord(input.into()));
+            Self::ToR => {
+                // (n -- ) (R: -- n)
+                let value = state.pop()?;
+                state.rpush(value);
             }
-        };
-        Ok(builtin)
-    }
-}
-
-#[derive(Debug)]
-pub struct State {
-    dictionary: HashMap<String, Token>,
-    stack: Vec<f64>,
-    positions: Vec<usize>,
-    tokens: Vec<Vec<Token>>,
-}
-
-impl State {
-    fn new() -> Self {
-        Self {
-            dictionary: HashMap::new(),
-            stack: Vec::new(),
-            positions: Vec::new(),
-            tokens: Vec::new(),
-        }
-    }
-
-    pub fn push_tokens(&mut self, tokens: &[Token]) {
-        self.tokens.push(tokens.to_vec());
-        self.positions.push(0);
-    }
-
-    pub fn pop_tokens(&mut self) {
-        self.tokens.pop();
-        self.positions.pop();
-    }
-
-    pub fn next_token(&mut self) -> Option<Token> {
-        match (self.tokens.last(), self.positions.last_mut()) {
-            (None, _) => None,
-            (_, None) => None,
-            (Some(tokens), Some(position)) => {
-                if *position < tokens.len() {
-                    let token = tokens[*position].clone();
-                    *position += 1;
-                    Some(token)
-                } else {
-                    None
+            Self::TwoToR => {
+                // (n1 n2 -- ) (R: -- n1 n2)
+                let (n2, n1) = state.pop2()?;
+                state.rpush(n1);
+                state.rpush(n2);
+            }
+            Self::Unloop => {
+                state.rpop2()?;
+            }
+            Self::Allot => {
+                // (n -- )
+                let n = state.pop()?;
+                state.allot(n)?;
+            }
+            Self::CComma => {
+                // (char -- )
+                let c = state.pop()?;
+                state.allot(1)?;
+                state.store_byte(state.here() - 1, c)?;
+            }
+            Self::CFetch => {
+                // (c-addr -- char)
+                let address = state.pop()?;
+                let c = state.fetch_byte(address)?;
+                state.push(c);
+            }
+            Self::CStore => {
+                // (char c-addr -- )
+                let (address, c) = state.pop2()?;
+                state.store_byte(address, c)?;
+            }
+            Self::CellPlus => {
+                // (addr -- addr)
+                let address = state.pop()?;
+                state.push(address.wrapping_add(CELL_SIZE));
+            }
+            Self::Cells => {
+                // (n -- n)
+                let n = state.pop()?;
+                state.push(n.wrapping_mul(CELL_SIZE));
+            }
+            Self::CharPlus => {
+                // (c-addr -- c-addr)
+                let address = state.pop()?;
+                state.push(address.wrapping_add(1));
+            }
+            Self::Chars => {
+                // (n -- n)
+                // Characters are a single address unit.
+            }
+            Self::Comma => {
+                // (x -- )
+                let x = state.pop()?;
+                state.allot(CELL_SIZE)?;
+                state.store(state.here() - CELL_SIZE, x)?;
+            }
+            Self::Fetch => {
+                // (addr -- x)
+                let address = state.pop()?;
+                let x = state.fetch(address)?;
+                state.push(x);
+            }
+            Self::Here => {
+                // ( -- addr)
+                state.push(state.here());
+            }
+            Self::PlusStore => {
+                // (n addr -- )
+                let (address, n) = state.pop2()?;
+                let x = state.fetch(address)?;
+                state.store(address, x.wrapping_add(n))?;
+            }
+            Self::Store => {
+                // (x addr -- )
+                let (address, x) = state.pop2()?;
+                state.store(address, x)?;
+            }
+            Self::Count => {
+                // (c-addr -- c-addr u)
+                let address = state.pop()?;
+                let len = state.fetch_byte(address)?;
+                state.push(address.wrapping_add(1));
+                state.push(len);
+            }
+            Self::Type => {
+                // (c-addr u -- )
+                let (len, address) = state.pop2()?;
+                let bytes = state.bytes(address, usize::try_from(len).unwrap_or(0))?;
+                let text = String::from_utf8_lossy(bytes).into_owned();
+                state.print(format_args!("{}", text))?;
+            }
+            Self::Arg => {
+                // (n -- c-addr u)
+                let n = state.pop()?;
+                let (address, len) = usize::try_from(n)
+                    .ok()
+                    .and_then(|n| state.args.get(n).copied())
+                    .unwrap_or_default();
+                state.push(address as i64);
+                state.push(len as i64);
+            }
+            Self::Argc => {
+                // ( -- n)
+                state.push(state.args.len() as i64);
+            }
+            Self::Accept => {
+                // (c-addr +n1 -- +n2)
+                let (max, address) = state.pop2()?;
+                let line = state.reader()?.read_line().map_err(input_error)?;
+                let line = line.unwrap_or_default();
+                let len = line.len().min(usize::try_from(max).unwrap_or(0));
+                state
+                    .bytes_mut(address, len)?
+                    .copy_from_slice(&line.as_bytes()[..len]);
+                state.push(len as i64);
+            }
+            Self::Key => {
+                // ( -- char)
+                match state.reader()?.read_char().map_err(input_error)? {
+                    Some(c) => state.push(c as i64),
+                    None => return Err(ForthError::EndOfInput),
                 }
             }
-        }
-    }
-
-    fn define_word(&mut self, word: String, value: Token) {
-        self.dictionary.insert(word.to_lowercase(), value);
-    }
-
-    fn lookup(&self, word: &str) -> Option<Token> {
-        self.dictionary.get(&word.to_lowercase()).cloned()
-    }
-
-    fn top(&self) -> Result<f64, ForthError> {
-        match self.stack.last() {
-            Some(value) => Ok(*value),
-            None => Err(ForthError::StackUnderflow),
-        }
-    }
+            Self::KeyQuestion => {
+                // ( -- flag)
+                let ready = state.reader()?.char_ready().map_err(input_error)?;
+                state.push(flag(ready));
+            }
+            Self::Refill => {
+                // ( -- flag)
+                let refilled = state.refill()?;
+                state.push(flag(refilled));
+            }
+            Self::Source => {
+                // ( -- c-addr u)
+                let len = state.inputs.last().map_or(0, |input| input.text().len());
+                state.push(INPUT_BUFFER + CELL_SIZE);
+                state.push(len as i64);
+            }
+            Self::ToIn => {
+                // ( -- addr)
+                state.push(INPUT_BUFFER);
+            }
+            Self::Include | Self::Require => {
+                // ( -- )
+                let name = state.parse_name()?;
+                state.include(&name, *self == Self::Require)?;
+            }
+            Self::Included | Self::Required => {
+                // (c-addr u -- )
+                let (len, address) = state.pop2()?;
+                let bytes = state.bytes(address, usize::try_from(len).unwrap_or(0))?;
+                let name = String::from_utf8_lossy(bytes).into_owned();
+                state.include(&name, *self == Self::Required)?;
+            }
+            Self::Constant => {
+                // (x -- )
+                let name = state.parse_name()?;
+                let x = state.pop()?;
+                state.define_word(name, Entry::colon(vec![Token::Number(x)]));
+            }
+            Self::To => {
+                // (x -- )
+                let name = state.parse_name()?;
+                match state.lookup(&name).and_then(|entry| entry.value_address()) {
+                    Some(address) => {
+                        let x = state.pop()?;
+                        state.store(address as i64, x)?;
+                    }
+                    None => return Err(ForthError::InvalidWord(name)),
+                }
+            }
+            Self::Value => {
+                // (x -- )
+                let name = state.parse_name()?;
+                let x = state.pop()?;
+                let address = state.here();
+                state.allot(CELL_SIZE)?;
+                state.store(address, x)?;
+                state.define_word(name, Entry::colon(vec![Token::Value(address as usize)]));
+            }
+            Self::Variable => {
+                // ( -- )
+                let name = state.parse_name()?;
+                let address = state.here();
+                state.allot(CELL_SIZE)?;
+                state.store(address, 0)?;
+                state.define_word(name, Entry::created(address as usize));
+            }
+            Self::Create => {
+                // ( -- )
+                let name = state.parse_name()?;
+                let address = state.here();
+                state.define_word(name, Entry::created(address as usize));
+            }
+            Self::DAdd => {
+                // (d1 d2 -- sum)
+                let (d2, d1) = (state.pop_double()?, state.pop_double()?);
+                state.push_double(d1.wrapping_add(d2));
+            }
+            Self::DSubtract => {
+                // (d1 d2 -- difference)
+                let (d2, d1) = (state.pop_double()?, state.pop_double()?);
+                state.push_double(d1.wrapping_sub(d2));
+            }
+            Self::DLess => {
+                // (d1 d2 -- flag)
+                let (d2, d1) = (state.pop_double()?, state.pop_double()?);
+                state.push(flag(d1 < d2));
+            }
+            Self::DEqual => {
+                // (d1 d2 -- flag)
+                let (d2, d1) = (state.pop_double()?, state.pop_double()?);
+                state.push(flag(d1 == d2));
+            }
+            Self::DZeroEqual => {
+                // (d -- flag)
+                let d = state.pop_double()?;
+                state.push(flag(d == 0));
+            }
+            Self::DAbs => {
+                // (d -- ud)
+                let d = state.pop_double()?;
+                state.push_double(d.wrapping_abs());
+            }
+            Self::DDisplay => {
+                // (d -- )
+                let d = state.pop_double()?;
+                state.print(format_args!("{} ", d))?;
+            }
+            Self::DNegate => {
+                // (d -- -d)
+                let d = state.pop_double()?;
+                state.push_double(d.wrapping_neg());
+            }
+            Self::DToS => {
+                // (d -- n)
+                let d = state.pop_double()?;
+                state.push(d as i64);
+            }
+            Self::StoD => {
+                // (n -- d)
+                let n = state.pop()?;
+                state.push_double(n.into());
+            }
+            Self::MStar => {
+                // (n1 n2 -- d)
+                let (n2, n1) = state.pop2()?;
+                state.push_double(i128::from(n1) * i128::from(n2));
+            }
+            Self::UMStar => {
+                // (u1 u2 -- ud)
+                let (u2, u1) = state.pop2()?;
+                state.push_double((u128::from(u1 as u64) * u128::from(u2 as u64)) as i128);
+            }
+            Self::UMSlashMod => {
+                // (ud u1 -- rem quot)
+                let u1 = state.pop()? as u64;
+                let ud = state.pop_double()? as u128;
+                if u1 == 0 {
+                    return Err(ForthError::DivisionByZero);
+                }
+                state.push((ud % u128::from(u1)) as i64);
+                state.push((ud / u128::from(u1)) as i64);
+            }
+            Self::SMSlashRem => {
+                // (d n -- rem quot)
+                let n = state.pop()?;
+                let d = state.pop_double()?;
+                let (rem, quot) = symmetric_div_mod(d, n.into())?;
+                state.push(rem as i64);
+                state.push(quot as i64);
+            }
+            Self::FMSlashMod => {
+                // (d n -- rem quot)
+                let n = state.pop()?;
+                let d = state.pop_double()?;
+                let (rem, quot) = floored_div_mod(d, n.into())?;
+                state.push(rem as i64);
+                state.push(quot as i64);
+            }
+            Self::StarSlash => {
+                // (n1 n2 n3 -- quot)
+                // The intermediate product is a double, so it cannot overflow.
+                let n3 = state.pop()?;
+                let (n2, n1) = state.pop2()?;
+                let (_, quot) = floored_div_mod(i128::from(n1) * i128::from(n2), n3.into())?;
+                state.push(quot as i64);
+            }
+            Self::StarSlashMod => {
+                // (n1 n2 n3 -- rem quot)
+                let n3 = state.pop()?;
+                let (n2, n1) = state.pop2()?;
+                let (rem, quot) = floored_div_mod(i128::from(n1) * i128::from(n2), n3.into())?;
+                state.push(rem as i64);
+                state.push(quot as i64);
+            }
+            Self::FAdd => {
+                // (F: r1 r2 -- sum)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r1 + r2);
+            }
+            Self::FSubtract => {
+                // (F: r1 r2 -- difference)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r1 - r2);
+            }
+            Self::FMultiply => {
+                // (F: r1 r2 -- result)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r1 * r2);
+            }
+            Self::FDivide => {
+                // (F: r1 r2 -- result)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r1 / r2);
+            }
+            Self::FLess => {
+                // ( -- flag) (F: r1 r2 -- )
+                let (r2, r1) = state.fpop2()?;
+                state.push(flag(r1 < r2));
+            }
+            Self::FZeroEqual => {
+                // ( -- flag) (F: r -- )
+                let r = state.fpop()?;
+                state.push(flag(r == 0.0));
+            }
+            Self::FZeroLess => {
+                // ( -- flag) (F: r -- )
+                let r = state.fpop()?;
+                state.push(flag(r < 0.0));
+            }
+            Self::FAbs => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.abs());
+            }
+            Self::FCos => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.cos());
+            }
+            Self::FDisplay => {
+                // (F: r -- )
+                let r = state.fpop()?;
+                state.print(format_args!("{} ", format_float(r)))?;
+            }
+            Self::FDrop => {
+                // (F: r -- )
+                state.fpop()?;
+            }
+            Self::FDup => {
+                // (F: r -- r r)
+                let r = state.ftop()?;
+                state.fpush(r);
+            }
+            Self::FExp => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.exp());
+            }
+            Self::FFetch => {
+                // (f-addr -- ) (F: -- r)
+                let address = state.pop()?;
+                let r = state.fetch_float(address)?;
+                state.fpush(r);
+            }
+            Self::FLn => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.ln());
+            }
+            Self::FNegate => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(-r);
+            }
+            Self::FOver => {
+                // (F: r1 r2 -- r1 r2 r1)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r1);
+                state.fpush(r2);
+                state.fpush(r1);
+            }
+            Self::FRot => {
+                // (F: r1 r2 r3 -- r2 r3 r1)
+                let (r3, r2) = state.fpop2()?;
+                let r1 = state.fpop()?;
+                state.fpush(r2);
+                state.fpush(r3);
+                state.fpush(r1);
+            }
+            Self::FShow => {
+                state.show_float_stack()?;
+            }
+            Self::FSin => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.sin());
+            }
+            Self::FSqrt => {
+                // (F: r -- r)
+                let r = state.fpop()?;
+                state.fpush(r.sqrt());
+            }
+            Self::FStore => {
+                // (f-addr -- ) (F: r -- )
+                let address = state.pop()?;
+                let r = state.fpop()?;
+                state.store_float(address, r)?;
+            }
+            Self::FSwap => {
+                // (F: r1 r2 -- r2 r1)
+                let (r2, r1) = state.fpop2()?;
+                state.fpush(r2);
+                state.fpush(r1);
+            }
+            Self::FToS => {
+                // ( -- n) (F: r -- )
+                let r = state.fpop()?;
+                state.push(r.trunc() as i64);
+            }
+            Self::FloatPlus => {
+                // (f-addr -- f-addr)
+                let address = state.pop()?;
+                state.push(address.wrapping_add(FLOAT_SIZE));
+            }
+            Self::Floats => {
+                // (n -- n)
+                let n = state.pop()?;
+                state.push(n.wrapping_mul(FLOAT_SIZE));
+            }
+            Self::SToF => {
+                // (n -- ) (F: -- r)
+                let n = state.pop()?;
+                state.fpush(n as f64);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+// The name of every builtin word, as it is looked up in lowercase.
+const BUILTINS: &[(&str, ForthBuiltin)] = &[
+    (".", ForthBuiltin::Display),
+    ("+", ForthBuiltin::Add),
+    ("-", ForthBuiltin::Subtract),
+    ("*", ForthBuiltin::Multiply),
+    ("/", ForthBuiltin::Divide),
+    ("=", ForthBuiltin::Equal),
+    ("<>", ForthBuiltin::NotEqual),
+    ("<", ForthBuiltin::Less),
+    (">", ForthBuiltin::Greater),
+    ("0=", ForthBuiltin::ZeroEqual),
+    ("0<", ForthBuiltin::ZeroLess),
+    ("0>", ForthBuiltin::ZeroGreater),
+    ("abs", ForthBuiltin::Abs),
+    ("and", ForthBuiltin::And),
+    ("bye", ForthBuiltin::Bye),
+    ("quit", ForthBuiltin::Bye),
+    ("cr", ForthBuiltin::CR),
+    ("dup", ForthBuiltin::Dup),
+    ("2dup", ForthBuiltin::TwoDup),
+    ("drop", ForthBuiltin::Drop),
+    ("2drop", ForthBuiltin::TwoDrop),
+    ("emit", ForthBuiltin::Emit),
+    ("exit", ForthBuiltin::Exit),
+    ("i", ForthBuiltin::I),
+    ("invert", ForthBuiltin::Invert),
+    ("j", ForthBuiltin::J),
+    ("lshift", ForthBuiltin::LShift),
+    ("max", ForthBuiltin::Max),
+    ("min", ForthBuiltin::Min),
+    ("/mod", ForthBuiltin::SlashMod),
+    ("mod", ForthBuiltin::Mod),
+    ("negate", ForthBuiltin::Negate),
+    ("or", ForthBuiltin::Or),
+    ("over", ForthBuiltin::Over),
+    ("2over", ForthBuiltin::TwoOver),
+    ("rot", ForthBuiltin::Rot),
+    ("rshift", ForthBuiltin::RShift),
+    (".s", ForthBuiltin::Show),
+    (".rs", ForthBuiltin::ShowReturn),
+    (">r", ForthBuiltin::ToR),
+    ("2>r", ForthBuiltin::TwoToR),
+    ("r>", ForthBuiltin::RFrom),
+    ("2r>", ForthBuiltin::TwoRFrom),
+    ("r@", ForthBuiltin::RFetch),
+    ("2r@", ForthBuiltin::TwoRFetch),
+    ("rdrop", ForthBuiltin::RDrop),
+    ("space", ForthBuiltin::Space),
+    ("spaces", ForthBuiltin::Spaces),
+    ("swap", ForthBuiltin::Swap),
+    ("2swap", ForthBuiltin::TwoSwap),
+    ("unloop", ForthBuiltin::Unloop),
+    ("xor", ForthBuiltin::Xor),
+    ("allot", ForthBuiltin::Allot),
+    ("c,", ForthBuiltin::CComma),
+    ("c@", ForthBuiltin::CFetch),
+    ("c!", ForthBuiltin::CStore),
+    ("cell+", ForthBuiltin::CellPlus),
+    ("cells", ForthBuiltin::Cells),
+    ("char+", ForthBuiltin::CharPlus),
+    ("chars", ForthBuiltin::Chars),
+    (",", ForthBuiltin::Comma),
+    ("@", ForthBuiltin::Fetch),
+    ("here", ForthBuiltin::Here),
+    ("+!", ForthBuiltin::PlusStore),
+    ("!", ForthBuiltin::Store),
+    ("count", ForthBuiltin::Count),
+    ("type", ForthBuiltin::Type),
+    ("arg", ForthBuiltin::Arg),
+    ("argc", ForthBuiltin::Argc),
+    ("accept", ForthBuiltin::Accept),
+    ("key", ForthBuiltin::Key),
+    ("key?", ForthBuiltin::KeyQuestion),
+    ("refill", ForthBuiltin::Refill),
+    ("source", ForthBuiltin::Source),
+    (">in", ForthBuiltin::ToIn),
+    ("include", ForthBuiltin::Include),
+    ("included", ForthBuiltin::Included),
+    ("require", ForthBuiltin::Require),
+    ("required", ForthBuiltin::Required),
+    ("constant", ForthBuiltin::Constant),
+    ("create", ForthBuiltin::Create),
+    ("to", ForthBuiltin::To),
+    ("value", ForthBuiltin::Value),
+    ("variable", ForthBuiltin::Variable),
+    ("d+", ForthBuiltin::DAdd),
+    ("d-", ForthBuiltin::DSubtract),
+    ("d<", ForthBuiltin::DLess),
+    ("d=", ForthBuiltin::DEqual),
+    ("d0=", ForthBuiltin::DZeroEqual),
+    ("d.", ForthBuiltin::DDisplay),
+    ("dabs", ForthBuiltin::DAbs),
+    ("dnegate", ForthBuiltin::DNegate),
+    ("d>s", ForthBuiltin::DToS),
+    ("s>d", ForthBuiltin::StoD),
+    ("m*", ForthBuiltin::MStar),
+    ("um*", ForthBuiltin::UMStar),
+    ("um/mod", ForthBuiltin::UMSlashMod),
+    ("sm/rem", ForthBuiltin::SMSlashRem),
+    ("fm/mod", ForthBuiltin::FMSlashMod),
+    ("*/", ForthBuiltin::StarSlash),
+    ("*/mod", ForthBuiltin::StarSlashMod),
+    ("f+", ForthBuiltin::FAdd),
+    ("f-", ForthBuiltin::FSubtract),
+    ("f*", ForthBuiltin::FMultiply),
+    ("f/", ForthBuiltin::FDivide),
+    ("f<", ForthBuiltin::FLess),
+    ("f0=", ForthBuiltin::FZeroEqual),
+    ("f0<", ForthBuiltin::FZeroLess),
+    ("f.", ForthBuiltin::FDisplay),
+    ("f.s", ForthBuiltin::FShow),
+    ("fabs", ForthBuiltin::FAbs),
+    ("fcos", ForthBuiltin::FCos),
+    ("fdrop", ForthBuiltin::FDrop),
+    ("fdup", ForthBuiltin::FDup),
+    ("fexp", ForthBuiltin::FExp),
+    ("f@", ForthBuiltin::FFetch),
+    ("f!", ForthBuiltin::FStore),
+    ("float+", ForthBuiltin::FloatPlus),
+    ("floats", ForthBuiltin::Floats),
+    ("fln", ForthBuiltin::FLn),
+    ("fnegate", ForthBuiltin::FNegate),
+    ("fover", ForthBuiltin::FOver),
+    ("frot", ForthBuiltin::FRot),
+    ("fsin", ForthBuiltin::FSin),
+    ("fsqrt", ForthBuiltin::FSqrt),
+    ("fswap", ForthBuiltin::FSwap),
+    ("f>s", ForthBuiltin::FToS),
+    ("s>f", ForthBuiltin::SToF),
+];
+
+impl TryFrom<&str> for ForthBuiltin {
+    type Error = ForthError;
+
+    fn try_from(input: &str) -> Result<ForthBuiltin, Self::Error> {
+        BUILTINS
+            .iter()
+            .find(|(name, _)| *name == input)
+            .map(|(_, builtin)| *builtin)
+            .ok_or_else(|| ForthError::UnknownWord(input.into()))
+    }
+}
+
+// Size in address units of a cell and of a float in data space.
+const CELL_SIZE: i64 = 8;
+const FLOAT_SIZE: i64 = 8;
+
+// Forth truth values: all bits set for true, none for false.
+fn flag(value: bool) -> i64 {
+    if value {
+        -1
+    } else {
+        0
+    }
+}
+
+// Shifting by a negative amount or by the cell width or more yields zero.
+fn shift_amount(u: i64) -> u32 {
+    u32::try_from(u).unwrap_or(u32::MAX)
+}
+
+// Division rounding the quotient towards negative infinity, so the
+// remainder takes the sign of the divisor. Returns (rem, quot). Both single
+// and double cells divide through here; callers truncate the results back
+// to a cell.
+fn floored_div_mod(n1: i128, n2: i128) -> Result<(i128, i128), ForthError> {
+    if n2 == 0 {
+        return Err(ForthError::DivisionByZero);
+    }
+    let mut quot = n1.wrapping_div(n2);
+    let mut rem = n1.wrapping_rem(n2);
+    if rem != 0 && (rem < 0) != (n2 < 0) {
+        quot = quot.wrapping_sub(1);
+        rem = rem.wrapping_add(n2);
+    }
+    Ok((rem, quot))
+}
+
+// Division rounding the quotient towards zero, so the remainder takes the
+// sign of the dividend. Returns (rem, quot).
+fn symmetric_div_mod(n1: i128, n2: i128) -> Result<(i128, i128), ForthError> {
+    if n2 == 0 {
+        return Err(ForthError::DivisionByZero);
+    }
+    Ok((n1.wrapping_rem(n2), n1.wrapping_div(n2)))
+}
+
+// Parse a double-cell literal, an integer followed by a `.` such as `1234.`
+fn parse_double(item: &str) -> Option<i128> {
+    item.strip_suffix('.')?.parse().ok()
+}
+
+// Print floats the way Forth systems do, with a trailing `.` marking
+// whole numbers as floating point.
+fn format_float(value: f64) -> String {
+    let formatted = value.to_string();
+    if value.is_finite() && !formatted.contains('.') {
+        formatted + "."
+    } else {
+        formatted
+    }
+}
+
+// Parse a Forth-2012 floating-point literal. Unlike Rust these require an
+// exponent marker, which may be followed by an empty exponent: `1e`, `1.5e0`,
+// `-2.5E-3`.
+fn parse_float(item: &str) -> Option<f64> {
+    let (significand, exponent) = item.split_once(['e', 'E'])?;
+
+    let digits = significand.trim_start_matches(['+', '-']);
+    if digits.len() + 1 < significand.len()
+        || !digits.chars().any(|c| c.is_ascii_digit())
+        || !digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        || digits.matches('.').count() > 1
+    {
+        return None;
+    }
+
+    let exponent_digits = exponent.trim_start_matches(['+', '-']);
+    if exponent_digits.len() + 1 < exponent.len()
+        || !exponent_digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let exponent = if exponent_digits.is_empty() {
+        "0"
+    } else {
+        exponent
+    };
+    format!("{}e{}", significand, exponent).parse().ok()
+}
+
+/// Bounds on the resources evaluation may use, for running scripts which
+/// are not trusted. `None` means unlimited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// Words executed in each call to [`Forth::eval`] or [`Forth::include`].
+    pub steps: Option<u64>,
+    /// Cells on the data stack, and numbers on the floating-point stack.
+    pub stack_depth: Option<usize>,
+    /// Cells on the return stack.
+    pub return_stack_depth: Option<usize>,
+    /// Definitions and source files being executed within one another.
+    pub call_depth: Option<usize>,
+    /// Source files being included within one another. Each takes far more
+    /// of the Rust stack than a definition does.
+    pub include_depth: Option<usize>,
+    /// Bytes of data space.
+    pub data_space: Option<usize>,
+    /// Wall-clock time for each call to [`Forth::eval`] or
+    /// [`Forth::include`]. Time spent waiting for input counts, but neither
+    /// the timeout nor [`Forth::interrupt_handle`] cuts short a read which is
+    /// waiting.
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// No limits at all. Deep enough recursion will overflow the Rust stack.
+    pub fn none() -> Self {
+        Self {
+            steps: None,
+            stack_depth: None,
+            return_stack_depth: None,
+            call_depth: None,
+            include_depth: None,
+            data_space: None,
+            timeout: None,
+        }
+    }
+}
+
+/// By default only nesting is limited, so runaway recursion fails rather
+/// than overflowing the Rust stack.
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            call_depth: Some(DEFAULT_CALL_DEPTH),
+            include_depth: Some(DEFAULT_INCLUDE_DEPTH),
+            ..Self::none()
+        }
+    }
+}
+
+// How many steps to take between checks of the clock.
+const CLOCK_INTERVAL: u64 = 1024;
+
+// Shallow enough for a debug build to fit in the 2 MiB stack of a spawned
+// thread.
+const DEFAULT_CALL_DEPTH: usize = 256;
+const DEFAULT_INCLUDE_DEPTH: usize = 16;
+
+fn exceeds(size: usize, limit: Option<usize>) -> bool {
+    limit.is_some_and(|limit| size > limit)
+}
+
+// Cut a stack back to its limit, if it is over it.
+fn trim<T>(stack: &mut Vec<T>, limit: Option<usize>) -> bool {
+    match limit {
+        Some(limit) if stack.len() > limit => {
+            stack.truncate(limit);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The interpreter's stacks, dictionary and data space, as seen by host
+/// words registered with [`Forth::register`].
+#[derive(Debug)]
+pub struct State {
+    dictionary: HashMap<String, Rc<Entry>>,
+    // Words implemented by the host, consulted before the builtins.
+    host_words: HashMap<String, Rc<HostWord>>,
+    // Name of the most recent definition, for DOES>.
+    latest: Option<String>,
+    // Location of the word which caused the most recent error.
+    error_span: Option<Span>,
+    stack: Vec<i64>,
+    return_stack: Vec<i64>,
+    float_stack: Vec<f64>,
+    // Data space, addressed in bytes. HERE is the end of the vector.
+    memory: Vec<u8>,
+    // The address and length in data space of each command line argument.
+    args: Vec<(usize, usize)>,
+    // The lexemes of a colon definition which is still open at the end of
+    // the input, to be continued by the next input.
+    compiling: Option<Vec<Lexeme>>,
+    // The inputs being interpreted, innermost last.
+    inputs: Vec<Input>,
+    // Every source file included so far, for REQUIRE.
+    included: HashSet<PathBuf>,
+    output: Output,
+    reader: InputReader,
+    limits: Limits,
+    // Words executed so far in the current evaluation.
+    steps: u64,
+    // When the current evaluation times out.
+    deadline: Option<Instant>,
+    // Set from any thread to stop the current evaluation.
+    interrupt: Arc<AtomicBool>,
+    positions: Vec<usize>,
+    tokens: Vec<Rc<[Token]>>,
+}
+
+// Where printed text is sent.
+struct Output(Box<dyn Write>);
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+// Adapts a callback taking text to `io::Write`.
+struct CallbackWriter<F>(F);
+
+impl<F: FnMut(&str)> Write for CallbackWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Input being interpreted, and the frame its tokens are run from.
+#[derive(Debug)]
+struct Input {
+    path: Option<PathBuf>,
+    frame: usize,
+    // The >IN cell followed by the text of the input, addressable from
+    // INPUT_BUFFER.
+    buffer: Vec<u8>,
+    // Where each token in the frame came from within the text.
+    ranges: Vec<Range<usize>>,
+}
+
+impl Input {
+    fn buffer(text: &str) -> Vec<u8> {
+        let mut buffer = vec![0; CELL_SIZE as usize];
+        buffer.extend_from_slice(text.as_bytes());
+        buffer
+    }
+
+    fn text(&self) -> &[u8] {
+        &self.buffer[CELL_SIZE as usize..]
+    }
+
+    // The offset of the parse position within the text.
+    fn to_in(&self) -> usize {
+        let to_in = i64::from_le_bytes(self.buffer[..CELL_SIZE as usize].try_into().unwrap());
+        usize::try_from(to_in).unwrap_or(0).min(self.text().len())
+    }
+
+    fn set_to_in(&mut self, to_in: usize) {
+        self.buffer[..CELL_SIZE as usize].copy_from_slice(&(to_in as i64).to_le_bytes());
+    }
+}
+
+/// Where KEY, KEY?, ACCEPT and REFILL read from. Every `BufRead` is a
+/// `Reader`.
+pub trait Reader {
+    /// The next character, or `None` at the end of the input.
+    fn read_char(&mut self) -> io::Result<Option<char>>;
+    /// Whether a character is available. This waits for input on sources
+    /// which cannot tell without reading.
+    fn char_ready(&mut self) -> io::Result<bool>;
+    /// The next line without its line ending, or `None` at the end of the
+    /// input.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+impl<R: io::BufRead> Reader for R {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = match self.fill_buf()?.first() {
+                Some(&byte) => byte,
+                None if bytes.is_empty() => return Ok(None),
+                None => return Ok(Some(char::REPLACEMENT_CHARACTER)),
+            };
+            self.consume(1);
+            bytes.push(byte);
+            match str::from_utf8(&bytes) {
+                Ok(text) => return Ok(text.chars().next()),
+                Err(err) if err.error_len().is_some() => {
+                    return Ok(Some(char::REPLACEMENT_CHARACTER));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn char_ready(&mut self) -> io::Result<bool> {
+        Ok(!self.fill_buf()?.is_empty())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if io::BufRead::read_line(self, &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+// Reads standard input, locking it only while reading so the REPL can read
+// it too.
+struct StdinReader;
+
+impl Reader for StdinReader {
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        io::stdin().lock().read_char()
+    }
+
+    fn char_ready(&mut self) -> io::Result<bool> {
+        io::stdin().lock().char_ready()
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        Reader::read_line(&mut io::stdin().lock())
+    }
+}
+
+struct InputReader(Box<dyn Reader>);
+
+impl fmt::Debug for InputReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("InputReader")
+    }
+}
+
+fn input_error(err: io::Error) -> ForthError {
+    ForthError::InputError(err.to_string())
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            dictionary: HashMap::new(),
+            host_words: HashMap::new(),
+            latest: None,
+            error_span: None,
+            stack: Vec::new(),
+            return_stack: Vec::new(),
+            float_stack: Vec::new(),
+            memory: Vec::new(),
+            args: Vec::new(),
+            compiling: None,
+            inputs: Vec::new(),
+            included: HashSet::new(),
+            output: Output(Box::new(io::stdout())),
+            reader: InputReader(Box::new(StdinReader)),
+            limits: Limits::default(),
+            steps: 0,
+            deadline: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            positions: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push_tokens(&mut self, tokens: Rc<[Token]>) {
+        self.tokens.push(tokens);
+        self.positions.push(0);
+    }
+
+    pub(crate) fn pop_tokens(&mut self) {
+        self.tokens.pop();
+        self.positions.pop();
+    }
+
+    pub(crate) fn next_token(&mut self) -> Option<Token> {
+        match (self.tokens.last(), self.positions.last_mut()) {
+            (None, _) => None,
+            (_, None) => None,
+            (Some(tokens), Some(position)) => {
+                if *position < tokens.len() {
+                    let token = tokens[*position].clone();
+                    *position += 1;
+                    Some(token)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // Count a word about to be executed against the step limit, and stop
+    // if evaluation has been interrupted or has run out of time.
+    fn step(&mut self) -> Result<(), ForthError> {
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(ForthError::Interrupted);
+        }
+        if self.limits.steps.is_some_and(|steps| self.steps >= steps) {
+            return Err(ForthError::StepLimitExceeded);
+        }
+        if self.steps.is_multiple_of(CLOCK_INTERVAL)
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(ForthError::TimedOut);
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    // Check the stacks once a word has executed. Words only push a few
+    // values each, so they cannot get far past the limits. A stack over
+    // its limit is cut back to it, so later evaluations can still use it.
+    // Data space is checked before it grows instead.
+    fn check_limits(&mut self) -> Result<(), ForthError> {
+        let limits = &self.limits;
+        if trim(&mut self.stack, limits.stack_depth) {
+            Err(ForthError::StackOverflow)
+        } else if trim(&mut self.float_stack, limits.stack_depth) {
+            Err(ForthError::FloatStackOverflow)
+        } else if trim(&mut self.return_stack, limits.return_stack_depth) {
+            Err(ForthError::ReturnStackOverflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Check there is room to run another definition or source file.
+    fn enter(&self) -> Result<(), ForthError> {
+        if exceeds(self.tokens.len() + 1, self.limits.call_depth) {
+            Err(ForthError::CallDepthExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn jump(&mut self, target: usize) {
+        if let Some(position) = self.positions.last_mut() {
+            *position = target;
+        }
+    }
+
+    // Return from the definition currently being executed.
+    pub(crate) fn exit(&mut self) {
+        if let (Some(tokens), Some(position)) = (self.tokens.last(), self.positions.last_mut()) {
+            *position = tokens.len();
+        }
+    }
+
+    // Read the next word from the input being interpreted, for words such as
+    // VARIABLE which take a name.
+    pub(crate) fn parse_name(&mut self) -> Result<String, ForthError> {
+        let frame = self.inputs.last().map_or(0, |input| input.frame);
+        let (tokens, position) = match (self.tokens.get(frame), self.positions.get_mut(frame)) {
+            (Some(tokens), Some(position)) => (tokens, position),
+            _ => return Err(ForthError::Unterminated),
+        };
+        let index = *position;
+        let token = tokens.get(index).cloned().ok_or(ForthError::Unterminated)?;
+        *position += 1;
+        self.parsed_token(index);
+        match token {
+            Token::Word(name, _) => Ok(name),
+            _ => Err(ForthError::InvalidWord(format!("{:?}", token))),
+        }
+    }
+
+    // Record where an error happened. The innermost word to fail is the one
+    // reported.
+    fn mark_error(&mut self, span: Span) {
+        if span != NO_SPAN {
+            self.error_span.get_or_insert(span);
+        }
+    }
+
+    fn define_word(&mut self, word: String, entry: Entry) {
+        let word = word.to_lowercase();
+        self.latest = Some(word.clone());
+        self.dictionary.insert(word, Rc::new(entry));
+    }
+
+    fn lookup(&self, word: &str) -> Option<Rc<Entry>> {
+        self.dictionary.get(&word.to_lowercase()).cloned()
+    }
+
+    // Replace the behavior of the most recent definition, which must have
+    // been made by CREATE.
+    fn set_behavior(&mut self, behavior: Rc<[Token]>) -> Result<(), ForthError> {
+        let name = self
+            .latest
+            .clone()
+            .ok_or_else(|| ForthError::InvalidWord("DOES>".into()))?;
+        match self.lookup(&name) {
+            Some(entry) if entry.data.is_some() => {
+                let data = entry.data;
+                self.define_word(name, Entry { data, behavior });
+                Ok(())
+            }
+            _ => Err(ForthError::InvalidWord(name)),
+        }
+    }
+
+    fn top(&self) -> Result<i64, ForthError> {
+        match self.stack.last() {
+            Some(value) => Ok(*value),
+            None => Err(ForthError::StackUnderflow),
+        }
+    }
+
+    /// The number of cells on the data stack.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
 
-    fn push(&mut self, value: f64) {
+    /// Push a cell onto the data stack.
+    pub fn push(&mut self, value: i64) {
         self.stack.push(value);
     }
 
-    fn pop(&mut self) -> Result<f64, ForthError> {
+    /// Pop a cell from the data stack.
+    pub fn pop(&mut self) -> Result<i64, ForthError> {
         match self.stack.pop() {
             Some(num) => Ok(num),
             None => Err(ForthError::StackUnderflow),
         }
     }
 
-    fn pop2(&mut self) -> Result<(f64, f64), ForthError> {
+    fn pop2(&mut self) -> Result<(i64, i64), ForthError> {
         match (self.stack.pop(), self.stack.pop()) {
             (Some(v1), Some(v2)) => Ok((v1, v2)),
             _ => Err(ForthError::StackUnderflow),
         }
     }
 
-    fn show_stack(&self) {
-        print!("<{}> ", self.stack.len());
-        for item in &self.stack {
-            print!("{} ", item);
+    fn here(&self) -> i64 {
+        self.memory.len() as i64
+    }
+
+    // Reserve `n` bytes of data space, or release them if negative.
+    fn allot(&mut self, n: i64) -> Result<(), ForthError> {
+        let here = self.here().wrapping_add(n);
+        let size = usize::try_from(here).map_err(|_| ForthError::InvalidAddress(here))?;
+        if let Some(additional) = size.checked_sub(self.memory.len()) {
+            self.reserve(additional)?;
         }
-        io::stdout().flush().unwrap();
+        self.memory.resize(size, 0);
+        Ok(())
     }
-}
 
+    // Make room for `additional` more bytes of data space, within the limit
+    // and the memory available.
+    fn reserve(&mut self, additional: usize) -> Result<(), ForthError> {
+        let size = self.memory.len().saturating_add(additional);
+        if exceeds(size, self.limits.data_space) {
+            return Err(ForthError::DataSpaceExhausted);
+        }
+        self.memory
+            .try_reserve(additional)
+            .map_err(|_| ForthError::DataSpaceExhausted)
+    }
+
+    // Check that `len` bytes starting at `address` lie within a region of
+    // `size` bytes starting at `base`, giving their offsets in the region.
+    fn address_range(
+        address: i64,
+        len: usize,
+        base: i64,
+        size: usize,
+    ) -> Result<Range<usize>, ForthError> {
+        usize::try_from(address.wrapping_sub(base))
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= size)
+            .ok_or(ForthError::InvalidAddress(address))
+    }
+
+    // The `len` bytes at `address`, in data space or the input buffer.
+    fn bytes(&self, address: i64, len: usize) -> Result<&[u8], ForthError> {
+        let (region, base) = match self.inputs.last() {
+            Some(input) if address >= INPUT_BUFFER => (&input.buffer, INPUT_BUFFER),
+            _ => (&self.memory, 0),
+        };
+        let range = Self::address_range(address, len, base, region.len())?;
+        Ok(&region[range])
+    }
+
+    fn bytes_mut(&mut self, address: i64, len: usize) -> Result<&mut [u8], ForthError> {
+        let (region, base) = match self.inputs.last_mut() {
+            Some(input) if address >= INPUT_BUFFER => (&mut input.buffer, INPUT_BUFFER),
+            _ => (&mut self.memory, 0),
+        };
+        let range = Self::address_range(address, len, base, region.len())?;
+        Ok(&mut region[range])
+    }
+
+    fn fetch(&self, address: i64) -> Result<i64, ForthError> {
+        let bytes = self.bytes(address, CELL_SIZE as usize)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn store(&mut self, address: i64, value: i64) -> Result<(), ForthError> {
+        let bytes = self.bytes_mut(address, CELL_SIZE as usize)?;
+        bytes.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn fetch_byte(&self, address: i64) -> Result<i64, ForthError> {
+        Ok(self.bytes(address, 1)?[0].into())
+    }
+
+    fn store_byte(&mut self, address: i64, value: i64) -> Result<(), ForthError> {
+        self.bytes_mut(address, 1)?[0] = value as u8;
+        Ok(())
+    }
+
+    // Copy a string to the end of data space, returning its address.
+    fn place_string(&mut self, text: &[u8]) -> Result<usize, ForthError> {
+        self.reserve(text.len())?;
+        let address = self.memory.len();
+        self.memory.extend_from_slice(text);
+        Ok(address)
+    }
+
+    // As `place_string`, preceded by a length byte.
+    fn place_counted_string(&mut self, text: &[u8]) -> Result<usize, ForthError> {
+        let len = u8::try_from(text.len()).map_err(|_| ForthError::StringTooLong(text.len()))?;
+        self.reserve(text.len() + 1)?;
+        let address = self.memory.len();
+        self.memory.push(len);
+        self.memory.extend_from_slice(text);
+        Ok(address)
+    }
+
+    fn fetch_float(&self, address: i64) -> Result<f64, ForthError> {
+        let bytes = self.bytes(address, FLOAT_SIZE as usize)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn store_float(&mut self, address: i64, value: f64) -> Result<(), ForthError> {
+        let bytes = self.bytes_mut(address, FLOAT_SIZE as usize)?;
+        bytes.copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// Push a double-cell number, which occupies two cells with the most
+    /// significant cell on top.
+    pub fn push_double(&mut self, value: i128) {
+        self.push(value as i64);
+        self.push((value >> 64) as i64);
+    }
+
+    /// Pop a double-cell number.
+    pub fn pop_double(&mut self) -> Result<i128, ForthError> {
+        let (high, low) = self.pop2()?;
+        Ok(i128::from(high) << 64 | i128::from(low as u64))
+    }
+
+    /// Copy a string into data space and push its address and length, as
+    /// S" does.
+    pub fn push_string(&mut self, text: &str) -> Result<(), ForthError> {
+        let address = self.place_string(text.as_bytes())?;
+        self.push(address as i64);
+        self.push(text.len() as i64);
+        Ok(())
+    }
+
+    /// Pop the address and length of a string and read it from data space.
+    /// Bytes which are not UTF-8 are replaced.
+    pub fn pop_string(&mut self) -> Result<String, ForthError> {
+        let (len, address) = self.pop2()?;
+        let len = usize::try_from(len).map_err(|_| ForthError::InvalidAddress(address))?;
+        let bytes = self.bytes(address, len)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// Push a number onto the floating-point stack.
+    pub fn fpush(&mut self, value: f64) {
+        self.float_stack.push(value);
+    }
+
+    fn ftop(&self) -> Result<f64, ForthError> {
+        match self.float_stack.last() {
+            Some(value) => Ok(*value),
+            None => Err(ForthError::FloatStackUnderflow),
+        }
+    }
+
+    /// Pop a number from the floating-point stack.
+    pub fn fpop(&mut self) -> Result<f64, ForthError> {
+        match self.float_stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(ForthError::FloatStackUnderflow),
+        }
+    }
+
+    fn fpop2(&mut self) -> Result<(f64, f64), ForthError> {
+        match (self.float_stack.pop(), self.float_stack.pop()) {
+            (Some(v1), Some(v2)) => Ok((v1, v2)),
+            _ => Err(ForthError::FloatStackUnderflow),
+        }
+    }
+
+    fn rpush(&mut self, value: i64) {
+        self.return_stack.push(value);
+    }
+
+    fn rpop(&mut self) -> Result<i64, ForthError> {
+        match self.return_stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(ForthError::ReturnStackUnderflow),
+        }
+    }
+
+    fn rpop2(&mut self) -> Result<(i64, i64), ForthError> {
+        match (self.return_stack.pop(), self.return_stack.pop()) {
+            (Some(v1), Some(v2)) => Ok((v1, v2)),
+            _ => Err(ForthError::ReturnStackUnderflow),
+        }
+    }
+
+    // Read the return stack `depth` items below the top.
+    fn rpick(&self, depth: usize) -> Result<i64, ForthError> {
+        self.return_stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|index| self.return_stack[index])
+            .ok_or(ForthError::ReturnStackUnderflow)
+    }
+
+    // Advance the innermost loop index by `increment`. When the index crosses
+    // the boundary between limit - 1 and limit the loop parameters are
+    // dropped and true is returned.
+    fn step_loop(&mut self, increment: i64) -> Result<bool, ForthError> {
+        let index = self.rpop()?;
+        let limit = self.rpick(0)?;
+        let before = index.wrapping_sub(limit);
+        let after = before.wrapping_add(increment);
+        // The boundary was crossed if the sign of index - limit changed in
+        // the direction of the increment.
+        if (before ^ after) & (before ^ increment) < 0 {
+            self.rpop()?;
+            Ok(true)
+        } else {
+            self.rpush(index.wrapping_add(increment));
+            Ok(false)
+        }
+    }
+
+    /// Print to the interpreter's output, as in
+    /// `state.print(format_args!("{} ", n))`.
+    pub fn print(&mut self, text: fmt::Arguments) -> Result<(), ForthError> {
+        self.output
+            .0
+            .write_fmt(text)
+            .map_err(|err| ForthError::OutputError(err.to_string()))
+    }
+
+    // The reader, once everything printed so far has been flushed so that
+    // any prompt is shown before waiting for input.
+    fn reader(&mut self) -> Result<&mut dyn Reader, ForthError> {
+        self.flush()?;
+        Ok(&mut *self.reader.0)
+    }
+
+    fn flush(&mut self) -> Result<(), ForthError> {
+        self.output
+            .0
+            .flush()
+            .map_err(|err| ForthError::OutputError(err.to_string()))
+    }
+
+    fn show_stack(&mut self) -> Result<(), ForthError> {
+        let text = Self::format_items(self.stack.iter());
+        self.print(format_args!("{}", text))
+    }
+
+    fn show_return_stack(&mut self) -> Result<(), ForthError> {
+        let text = Self::format_items(self.return_stack.iter());
+        self.print(format_args!("{}", text))
+    }
+
+    fn show_float_stack(&mut self) -> Result<(), ForthError> {
+        let text = Self::format_items(self.float_stack.iter().map(|item| format_float(*item)));
+        self.print(format_args!("{}", text))
+    }
+
+    // The depth of a stack followed by its items, bottom first.
+    fn format_items<T: fmt::Display>(items: impl ExactSizeIterator<Item = T>) -> String {
+        let mut text = format!("<{}> ", items.len());
+        for item in items {
+            text += &format!("{} ", item);
+        }
+        text
+    }
+}
+
+// The outer interpreter, which reads source text and runs it.
+impl State {
+    // Interpret some input, which was read from `path` if it came from a
+    // file.
+    fn interpret(&mut self, input: &str, path: Option<PathBuf>) -> Result<(), ForthError> {
+        let lexemes = self.lex(input)?;
+        if lexemes.is_empty() {
+            Ok(())
+        } else {
+            let (tokens, ranges) = self.tokenize(&lexemes)?;
+            self.inputs.push(Input {
+                path,
+                frame: self.tokens.len(),
+                buffer: Input::buffer(input),
+                ranges,
+            });
+            let result = self.run(&tokens);
+            self.inputs.pop();
+            result
+        }
+    }
+
+    fn run(&mut self, tokens: &[Token]) -> Result<(), ForthError> {
+        self.enter()?;
+        self.push_tokens(tokens.into());
+        let result = self.run_tokens();
+        self.pop_tokens();
+        result
+    }
+
+    fn run_tokens(&mut self) -> Result<(), ForthError> {
+        let frame = self.tokens.len() - 1;
+        let parsing = self.inputs.last().is_some_and(|input| input.frame == frame);
+
+        while let Some(token) = self.next_token() {
+            // >IN points past the word being interpreted, and interpreting
+            // carries on from wherever the word leaves it.
+            if parsing {
+                self.parsed_token(self.positions[frame] - 1);
+            }
+            self.step()?;
+            if let Some(num) = token.eval(self)? {
+                self.push(num);
+            }
+            self.check_limits()?;
+            if parsing {
+                self.seek_to_in();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Set >IN to the end of a token in the current input.
+    fn parsed_token(&mut self, index: usize) {
+        if let Some(input) = self.inputs.last_mut() {
+            let end = input.ranges.get(index).map_or(0, |range| range.end);
+            input.set_to_in(end);
+        }
+    }
+
+    // Continue interpreting the current input from the first token at or
+    // after >IN.
+    fn seek_to_in(&mut self) {
+        if let Some(input) = self.inputs.last() {
+            let to_in = input.to_in();
+            let index = input.ranges.partition_point(|range| range.start < to_in);
+            self.positions[input.frame] = index;
+        }
+    }
+
+    // Move on to the next line of the current input. Past the last line of
+    // input which did not come from a file, a line is read from the reader
+    // and interpreted next instead.
+    fn refill(&mut self) -> Result<bool, ForthError> {
+        let input = match self.inputs.last_mut() {
+            Some(input) => input,
+            None => return Ok(false),
+        };
+        let to_in = input.to_in();
+        if let Some(newline) = input.text()[to_in..].iter().position(|&c| c == b'\n') {
+            input.set_to_in(to_in + newline + 1);
+            return Ok(true);
+        }
+        if input.path.is_some() {
+            return Ok(false);
+        }
+
+        let line = match self.reader()?.read_line().map_err(input_error)? {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+        let lexemes = self.lex(&line)?;
+        let (tokens, ranges) = self.tokenize(&lexemes)?;
+        if let Some(input) = self.inputs.last_mut() {
+            input.buffer = Input::buffer(&line);
+            input.ranges = ranges;
+            self.tokens[input.frame] = tokens.into();
+        }
+        Ok(true)
+    }
+
+    // Interpret the named source file. A relative name is found next to the
+    // file being interpreted, if there is one. With `once`, a file which has
+    // already been included is skipped.
+    fn include(&mut self, name: &str, once: bool) -> Result<(), ForthError> {
+        let files = self.inputs.iter().filter(|input| input.path.is_some());
+        if exceeds(files.count() + 1, self.limits.include_depth) {
+            return Err(ForthError::IncludeDepthExceeded);
+        }
+        let path = match self.inputs.last().and_then(|input| input.path.as_ref()) {
+            Some(current) => current.with_file_name(name),
+            None => PathBuf::from(name),
+        };
+        let file_error = |err: io::Error| ForthError::FileError(name.to_string(), err.to_string());
+        let canonical = path.canonicalize().map_err(file_error)?;
+        if once && self.included.contains(&canonical) {
+            return Ok(());
+        }
+        let input = fs::read_to_string(&path).map_err(file_error)?;
+        self.included.insert(canonical);
+
+        // The file is interpreted on its own, so a definition being compiled
+        // from the including input waits until it is done.
+        let compiling = self.compiling.take();
+        let error_span = self.error_span.take();
+        let mut result = self.interpret(&input, Some(path.clone()));
+        if result.is_ok() && self.compiling.is_some() {
+            result = Err(ForthError::Unterminated);
+        }
+        let span = mem::replace(&mut self.error_span, error_span);
+        self.compiling = compiling;
+
+        result.map_err(|error| match error {
+            ForthError::UserQuit => error,
+            error => ForthError::Included {
+                location: match span {
+                    Some(span) => format!("{}:{}", path.display(), span.line),
+                    None => path.display().to_string(),
+                },
+                error: Box::new(error),
+            },
+        })
+    }
+
+    // Split the input into whitespace separated lexemes, noting where each
+    // one starts. Comments are dropped here, and parsing words collect the
+    // text following them.
+    fn lex(&self, input: &str) -> Result<Vec<Lexeme>, ForthError> {
+        let mut scanner = Scanner::new(input);
+        let mut lexemes = Vec::new();
+
+        while let Some(mut lexeme) = scanner.word() {
+            match lexeme.text.as_str() {
+                "\\" | "#!" => {
+                    scanner.parse('\n');
+                }
+                // Allow a script to start with an interpreter line.
+                text if text.starts_with("#!")
+                    && lexeme.span.line == 1
+                    && lexeme.span.column == 1 =>
+                {
+                    scanner.parse('\n');
+                }
+                "(" => {
+                    scanner.parse(')');
+                }
+                ".(" => {
+                    lexeme.parsed = Some(scanner.parse(')').into_bytes());
+                    lexeme.range.end = scanner.offset;
+                    lexemes.push(lexeme);
+                }
+                text if STRING_WORDS.contains(&text.to_lowercase().as_str()) => {
+                    lexeme.parsed = Some(if text.eq_ignore_ascii_case("s\\\"") {
+                        scanner.parse_escaped()
+                    } else {
+                        scanner.parse('"').into_bytes()
+                    });
+                    lexeme.range.end = scanner.offset;
+                    lexemes.push(lexeme);
+                }
+                _ => lexemes.push(lexeme),
+            }
+        }
+
+        Ok(lexemes)
+    }
+
+    // Turn lexemes into tokens to run, noting where in the input each token
+    // came from.
+    fn tokenize(
+        &mut self,
+        input: &[Lexeme],
+    ) -> Result<(Vec<Token>, Vec<Range<usize>>), ForthError> {
+        let mut tokens = Vec::new();
+        let mut ranges = Vec::new();
+        let mut lexemes = input.iter();
+        // Where the definition being compiled starts in this input.
+        let mut start = 0;
+        if let Some(definition) = &mut self.compiling {
+            for item in definition {
+                item.span = NO_SPAN;
+            }
+        }
+
+        loop {
+            if let Some(mut definition) = self.compiling.take() {
+                loop {
+                    match lexemes.next() {
+                        Some(item) if item.text == ";" => {
+                            ranges.push(start..item.range.end);
+                            break;
+                        }
+                        Some(item) => definition.push(item.clone()),
+                        None => {
+                            // Keep compiling with the next input.
+                            self.compiling = Some(definition);
+                            return Ok((tokens, ranges));
+                        }
+                    }
+                }
+                tokens.push(Token::UserDefined(definition));
+            }
+
+            match lexemes.next() {
+                Some(item) if item.text == ":" => {
+                    start = item.range.start;
+                    self.compiling = Some(Vec::new());
+                }
+                Some(item) => {
+                    ranges.push(item.range.clone());
+                    tokens.push(if item.text == ";" {
+                        Token::UserDefined(Vec::new())
+                    } else {
+                        self.parse_lexeme(item)
+                    });
+                }
+                None => return Ok((tokens, ranges)),
+            }
+        }
+    }
+
+    fn parse_lexeme(&self, item: &Lexeme) -> Token {
+        let text = item.text.as_str();
+        if let Some(parsed) = &item.parsed {
+            match text.to_lowercase().as_str() {
+                "c\"" => Token::CountedString(parsed.clone()),
+                "s\"" | "s\\\"" => Token::String(parsed.clone()),
+                // Only S\" can parse text which is not UTF-8.
+                _ => Token::Print(String::from_utf8_lossy(parsed).into_owned()),
+            }
+        } else if let Ok(value) = text.parse() {
+            Token::Number(value)
+        } else if let Some(value) = parse_double(text) {
+            Token::Double(value)
+        } else if let Some(value) = parse_float(text) {
+            Token::Float(value)
+        } else {
+            Token::Word(item.text.clone(), item.span)
+        }
+    }
+
+    // Turn the lexemes between `:` and `;` into the name of the definition
+    // followed by its body, compiling control flow words into branches.
+    fn compile_definition(&mut self, input: &[Lexeme]) -> Result<Vec<Token>, ForthError> {
+        let (name, input) = match input.split_first() {
+            Some((name, input)) => (self.parse_lexeme(name), input),
+            None => return Ok(Vec::new()),
+        };
+
+        // The code following each DOES> is compiled and resolved nested
+        // within the code before it, so DOES> counts against the call depth.
+        if let Some(limit) = self.limits.call_depth {
+            let mut does = input
+                .iter()
+                .filter(|item| item.text.eq_ignore_ascii_case("does>"));
+            if let Some(item) = does.nth(limit) {
+                self.mark_error(item.span);
+                return Err(ForthError::CallDepthExceeded);
+            }
+        }
+
+        let body = self.compile_body(input)?;
+
+        Ok(iter::once(name).chain(body).collect())
+    }
+
+    // Compile control flow words into branches within a definition body.
+    fn compile_body(&mut self, input: &[Lexeme]) -> Result<Vec<Token>, ForthError> {
+        let mut body = Vec::new();
+        // Open control structures, with the word which opened each.
+        let mut control: Vec<(Control, &Lexeme)> = Vec::new();
+
+        for (index, item) in input.iter().enumerate() {
+            let mut mismatch = |message: String| {
+                self.mark_error(item.span);
+                Err(ForthError::ControlMismatch(message))
+            };
+            match item.text.to_lowercase().as_str() {
+                "if" => {
+                    control.push((Control::If(body.len()), item));
+                    body.push(Token::BranchIfZero(0));
+                }
+                "else" => match control.pop() {
+                    Some((Control::If(origin), _)) => {
+                        control.push((Control::Else(body.len()), item));
+                        body.push(Token::Branch(0));
+                        resolve_branch(&mut body, origin);
+                    }
+                    _ => return mismatch("ELSE without IF".into()),
+                },
+                "then" => match control.pop() {
+                    Some((Control::If(origin), _))
+                    | Some((Control::Else(origin), _))
+                    | Some((Control::While(origin), _)) => {
+                        resolve_branch(&mut body, origin);
+                    }
+                    _ => return mismatch("THEN without IF".into()),
+                },
+                "do" => {
+                    body.push(Token::Do);
+                    let entry = Control::Do {
+                        start: body.len(),
+                        leaves: Vec::new(),
+                    };
+                    control.push((entry, item));
+                }
+                "?do" => {
+                    let origin = body.len();
+                    body.push(Token::QuestionDo(0));
+                    let entry = Control::Do {
+                        start: body.len(),
+                        leaves: vec![origin],
+                    };
+                    control.push((entry, item));
+                }
+                word @ ("loop" | "+loop") => match control.pop() {
+                    Some((Control::Do { start, leaves }, _)) => {
+                        body.push(if word == "loop" {
+                            Token::Loop(start)
+                        } else {
+                            Token::PlusLoop(start)
+                        });
+                        for origin in leaves {
+                            resolve_branch(&mut body, origin);
+                        }
+                    }
+                    _ => return mismatch(format!("{} without DO", word.to_uppercase())),
+                },
+                "begin" => control.push((Control::Begin(body.len()), item)),
+                "until" => match control.pop() {
+                    Some((Control::Begin(destination), _)) => {
+                        body.push(Token::BranchIfZero(destination));
+                    }
+                    _ => return mismatch("UNTIL without BEGIN".into()),
+                },
+                "again" => match control.pop() {
+                    Some((Control::Begin(destination), _)) => {
+                        body.push(Token::Branch(destination));
+                    }
+                    _ => return mismatch("AGAIN without BEGIN".into()),
+                },
+                "while" => match control.pop() {
+                    Some((Control::Begin(destination), begin)) => {
+                        control.push((Control::While(body.len()), item));
+                        control.push((Control::Begin(destination), begin));
+                        body.push(Token::BranchIfZero(0));
+                    }
+                    _ => return mismatch("WHILE without BEGIN".into()),
+                },
+                "repeat" => match (control.pop(), control.pop()) {
+                    (Some((Control::Begin(destination), _)), Some((Control::While(origin), _))) => {
+                        body.push(Token::Branch(destination));
+                        resolve_branch(&mut body, origin);
+                    }
+                    _ => return mismatch("REPEAT without BEGIN ... WHILE".into()),
+                },
+                "does>" => {
+                    if !control.is_empty() {
+                        return mismatch("DOES> inside control structure".into());
+                    }
+                    let behavior = self.compile_body(&input[index + 1..])?;
+                    body.push(Token::Does(behavior.into()));
+                    break;
+                }
+                "leave" => {
+                    let leaves = control.iter_mut().rev().find_map(|(entry, _)| match entry {
+                        Control::Do { leaves, .. } => Some(leaves),
+                        _ => None,
+                    });
+                    match leaves {
+                        Some(leaves) => {
+                            leaves.push(body.len());
+                            body.push(Token::Leave(0));
+                        }
+                        None => return mismatch("LEAVE outside DO".into()),
+                    }
+                }
+                // Printing the text of `.(` is immediate, even in a definition.
+                ".(" => {
+                    let text = item.parsed.as_deref().unwrap_or_default();
+                    self.print(format_args!("{}", String::from_utf8_lossy(text)))?;
+                }
+                _ => body.push(self.parse_lexeme(item)),
+            }
+        }
+
+        if let Some((unresolved, word)) = control.pop() {
+            let closer = match unresolved {
+                Control::If(_) | Control::Else(_) => "THEN",
+                Control::Do { .. } => "LOOP",
+                Control::Begin(_) => "UNTIL, AGAIN or REPEAT",
+                Control::While(_) => "REPEAT",
+            };
+            self.mark_error(word.span);
+            return Err(ForthError::ControlMismatch(format!(
+                "{} without {}",
+                word.text.to_uppercase(),
+                closer
+            )));
+        }
+
+        Ok(body)
+    }
+}
+
+/// A Forth interpreter. Its stacks, dictionary and data space persist
+/// from one evaluation to the next.
 #[derive(Debug)]
 pub struct Forth {
     state: State,
 }
 
+impl Default for Forth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Forth {
+    /// An interpreter which knows only the builtin words, printing to
+    /// standard output and reading standard input.
     pub fn new() -> Self {
         Self {
             state: State::new(),
         }
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
-        self.state.next_token()
+    /// Print to `output` rather than standard output.
+    pub fn with_output(mut self, output: impl Write + 'static) -> Self {
+        self.state.output = Output(Box::new(output));
+        self
+    }
+
+    /// Pass everything printed to `callback`.
+    pub fn with_output_callback(self, callback: impl FnMut(&str) + 'static) -> Self {
+        self.with_output(CallbackWriter(callback))
+    }
+
+    /// Restrict the resources evaluation may use.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.state.limits = limits;
+        self
+    }
+
+    /// A flag which, when set from any thread, stops the evaluation in
+    /// progress with [`ForthError::Interrupted`] before it executes another
+    /// word. The flag is cleared when each evaluation starts, so setting it
+    /// while nothing is being evaluated has no effect. Like
+    /// [`Limits::timeout`], it does not cut short a read which is waiting
+    /// for input.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.state.interrupt.clone()
+    }
+
+    /// Read input for KEY, ACCEPT and REFILL from `reader` rather than
+    /// standard input.
+    pub fn with_input(mut self, reader: impl Reader + 'static) -> Self {
+        self.state.reader = InputReader(Box::new(reader));
+        self
+    }
+
+    /// The prompt for the next line of input: `] ` while a definition is
+    /// being compiled, `> ` otherwise.
+    pub fn prompt(&self) -> String {
+        if self.is_compiling() {
+            "] ".to_string()
+        } else {
+            "> ".to_string()
+        }
+    }
+
+    /// Make the command line arguments available to ARGC and ARG, with the
+    /// name of the program or script first.
+    pub fn set_args(&mut self, args: &[String]) -> Result<(), ForthError> {
+        self.state.args = args
+            .iter()
+            .map(|arg| Ok((self.state.place_string(arg.as_bytes())?, arg.len())))
+            .collect::<Result<_, ForthError>>()?;
+        Ok(())
+    }
+
+    /// Whether a colon definition is waiting for more input.
+    pub fn is_compiling(&self) -> bool {
+        self.state.compiling.is_some()
+    }
+
+    /// Every word the interpreter knows, builtin or defined, in lowercase
+    /// and sorted.
+    pub fn words(&self) -> Vec<String> {
+        // Words which are handled while reading the input.
+        let syntax = [":", ";", "\\", "#!", "(", ".("];
+
+        let mut words: Vec<String> = BUILTINS
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(CONTROL_WORDS.iter().copied())
+            .chain(STRING_WORDS.iter().copied())
+            .chain(syntax.iter().copied())
+            .map(String::from)
+            .chain(self.state.host_words.keys().cloned())
+            .chain(self.state.dictionary.keys().cloned())
+            .collect();
+        words.sort();
+        words.dedup();
+        words
+    }
+
+    /// The number of cells on the data stack.
+    pub fn depth(&self) -> usize {
+        self.state.depth()
+    }
+
+    /// The data stack, with the top of the stack last.
+    pub fn stack(&self) -> &[i64] {
+        &self.state.stack
+    }
+
+    /// The return stack, with the top of the stack last.
+    pub fn return_stack(&self) -> &[i64] {
+        &self.state.return_stack
+    }
+
+    /// The floating-point stack, with the top of the stack last.
+    pub fn float_stack(&self) -> &[f64] {
+        &self.state.float_stack
+    }
+
+    /// Push a cell onto the data stack.
+    pub fn push(&mut self, value: i64) {
+        self.state.push(value);
+    }
+
+    /// Pop a cell from the data stack.
+    pub fn pop(&mut self) -> Result<i64, ForthError> {
+        self.state.pop()
+    }
+
+    /// Push a double-cell number onto the data stack, as a literal such as
+    /// `1234.` does.
+    pub fn push_double(&mut self, value: i128) {
+        self.state.push_double(value);
+    }
+
+    /// Pop a double-cell number from the data stack.
+    pub fn pop_double(&mut self) -> Result<i128, ForthError> {
+        self.state.pop_double()
+    }
+
+    /// Push a number onto the floating-point stack.
+    pub fn push_float(&mut self, value: f64) {
+        self.state.fpush(value);
+    }
+
+    /// Pop a number from the floating-point stack.
+    pub fn pop_float(&mut self) -> Result<f64, ForthError> {
+        self.state.fpop()
+    }
+
+    /// Copy a string into data space and push its address and length, as
+    /// S" does.
+    pub fn push_string(&mut self, text: &str) -> Result<(), ForthError> {
+        self.state.push_string(text)
+    }
+
+    /// Pop the address and length of a string from the data stack and read
+    /// it from data space. Bytes which are not UTF-8 are replaced.
+    pub fn pop_string(&mut self) -> Result<String, ForthError> {
+        self.state.pop_string()
+    }
+
+    /// Make `function` available as the word `name`. Host words take
+    /// precedence over builtins of the same name, and colon definitions
+    /// over host words.
+    pub fn register(
+        &mut self,
+        name: &str,
+        function: impl FnMut(&mut State) -> Result<(), ForthError> + 'static,
+    ) {
+        self.register_word(name, None, Box::new(function));
+    }
+
+    /// As [`Forth::register`], checking before each call that the data stack
+    /// holds the cells the word takes, and after it that the word left the
+    /// cells it declared.
+    pub fn register_with_effect(
+        &mut self,
+        name: &str,
+        effect: StackEffect,
+        function: impl FnMut(&mut State) -> Result<(), ForthError> + 'static,
+    ) {
+        self.register_word(name, Some(effect), Box::new(function));
+    }
+
+    fn register_word(
+        &mut self,
+        name: &str,
+        effect: Option<StackEffect>,
+        function: Box<HostFunction>,
+    ) {
+        let name = name.to_lowercase();
+        let word = HostWord {
+            name: name.clone(),
+            effect,
+            function: RefCell::new(function),
+        };
+        self.state.host_words.insert(name, Rc::new(word));
+    }
+
+    /// The location within the most recently evaluated input of the word
+    /// which caused it to fail. There is none if that word was read from an
+    /// earlier input, as part of a definition continued across inputs.
+    pub fn error_span(&self) -> Option<Span> {
+        self.state.error_span
+    }
+
+    /// Interpret the input, leaving any results on the stack. A colon
+    /// definition left open at the end of the input is continued by the
+    /// next call.
+    pub fn eval(&mut self, input: &str) -> Result<(), ForthError> {
+        self.start();
+        let result = self.state.interpret(input, None);
+        self.recover(result)
+    }
+
+    /// Interpret a source file, as INCLUDED does.
+    pub fn include(&mut self, path: &str) -> Result<(), ForthError> {
+        self.start();
+        let result = self.state.include(path, false);
+        self.recover(result)
+    }
+
+    // Give a new evaluation its full budget of steps and time.
+    // An interrupt which arrived since the last evaluation was meant for it,
+    // so it is dropped.
+    fn start(&mut self) {
+        self.state.error_span = None;
+        self.state.steps = 0;
+        self.state.interrupt.store(false, Ordering::Relaxed);
+        self.state.deadline = self
+            .state
+            .limits
+            .timeout
+            .map(|timeout| Instant::now() + timeout);
+    }
+
+    // Flush the output, and after an error return to interpreting, abandoning
+    // any open definition and any loops which were in progress. An error
+    // from evaluating takes precedence over one from flushing.
+    fn recover(&mut self, result: Result<(), ForthError>) -> Result<(), ForthError> {
+        let flushed = self.state.flush();
+        let result = result.and(flushed);
+        if result.is_err() {
+            self.state.compiling = None;
+            self.state.return_stack.clear();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn cannot_parse_letter() {
+        let mut forth = Forth::new();
+        assert_eq!(
+            forth.eval("1 a 3 4 5"),
+            Err(ForthError::UnknownWord("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_numbers() {
+        let mut forth = Forth::new();
+        assert_eq!(forth.eval("1 23 -3 4 5"), Ok(()));
+        assert_eq!(forth.stack(), vec![1, 23, -3, 4, 5]);
+    }
+
+    #[test]
+    fn parses_math_expressions() {
+        let mut forth = Forth::new();
+        let lexemes = forth.state.lex("1 23 + -3 * 4 / 5 -").unwrap();
+        let result = forth.state.tokenize(&lexemes).map(|(tokens, _)| tokens);
+        assert_eq!(
+            Ok(vec![
+                Token::Number(1),
+                Token::Number(23),
+                Token::Word(
+                    "+".to_string(),
+                    Span {
+                        line: 1,
+                        column: 6,
+                        len: 1
+                    }
+                ),
+                Token::Number(-3),
+                Token::Word(
+                    "*".to_string(),
+                    Span {
+                        line: 1,
+                        column: 11,
+                        len: 1
+                    }
+                ),
+                Token::Number(4),
+                Token::Word(
+                    "/".to_string(),
+                    Span {
+                        line: 1,
+                        column: 15,
+                        len: 1
+                    }
+                ),
+                Token::Number(5),
+                Token::Word(
+                    "-".to_string(),
+                    Span {
+                        line: 1,
+                        column: 19,
+                        len: 1
+                    }
+                ),
+            ]),
+            result
+        );
+    }
+
+    #[test]
+    fn simple_addition_works() {
+        let mut forth = Forth::new();
+        let lexemes = forth.state.lex("5 6 +").unwrap();
+        let (tokens, _) = forth.state.tokenize(&lexemes).unwrap();
+        forth.state.run(&tokens).unwrap();
+        assert_eq!(forth.stack(), vec![11]);
+    }
+
+    #[test]
+    fn dup() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1],);
+    }
+
+    #[test]
+    fn dup_top_value_only() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn dup_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 DUP Dup dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn dup_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("dup"));
+    }
+
+    #[test]
+    fn two_dup() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 2dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn two_dup_top_pair_only() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 2dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3, 2, 3]);
+    }
+
+    #[test]
+    fn two_dup_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 2DUP 2Dup 2dup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn two_dup_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("2dup"));
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 2dup"));
+    }
+
+    #[test]
+    fn rot() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 rot"), Ok(()));
+        assert_eq!(f.stack(), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn rot_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 ROT Rot rot"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rot_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("rot"));
+    }
+
+    #[test]
+    fn drop() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 drop"), Ok(()));
+        assert_eq!(Vec::<i64>::new(), f.stack());
+    }
+
+    #[test]
+    fn drop_with_two() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 drop"), Ok(()));
+        assert_eq!(f.stack(), vec![1]);
+    }
+
+    #[test]
+    fn drop_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 4 DROP Drop drop"), Ok(()));
+        assert_eq!(f.stack(), vec![1]);
+    }
+
+    #[test]
+    fn drop_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("drop"));
+    }
+
+    #[test]
+    fn swap() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 swap"), Ok(()));
+        assert_eq!(f.stack(), vec![2, 1]);
+    }
+
+    #[test]
+    fn swap_with_three() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 swap"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn swap_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 SWAP 3 Swap 4 swap"), Ok(()));
+        assert_eq!(f.stack(), vec![2, 3, 4, 1]);
+    }
+
+    #[test]
+    fn swap_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 swap"));
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("swap"));
+    }
+
+    #[test]
+    fn two_swap() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 4 2swap"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn two_swap_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 4 2SWAP 2Swap 2swap"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn two_swap_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 2swap"));
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("2swap"));
+    }
+
+    #[test]
+    fn over() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 over"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn over_with_three() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 over"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn over_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 OVER Over over"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn over_error() {
+        let mut f = Forth::new();
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 over"));
+        assert_eq!(Err(ForthError::StackUnderflow), f.eval("over"));
+    }
+
+    #[test]
+    fn two_over() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 4 2over"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn two_over_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 3 4 2OVER 2Over 2over"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3, 4, 1, 2, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn two_over_error() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2over"), Err(ForthError::StackUnderflow));
+        assert_eq!(f.eval("1 2 2over"), Err(ForthError::StackUnderflow));
+        assert_eq!(f.eval("1 2 3 2over"), Err(ForthError::StackUnderflow));
+    }
+
+    // User-defined words
+
+    #[test]
+    fn can_consist_of_built_in_words() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": dup-twice dup dup ;"), Ok(()));
+        assert_eq!(f.eval("1 dup-twice"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn execute_in_the_right_order() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": countup 1 2 3 ;"), Ok(()));
+        assert_eq!(f.eval("countup"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn redefining_an_existing_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo dup ;"), Ok(()));
+        assert_eq!(f.eval(": foo dup dup ;"), Ok(()));
+        assert_eq!(f.eval("1 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn redefining_an_existing_built_in_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": swap dup ;"), Ok(()));
+        assert_eq!(f.eval("1 swap"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1]);
+    }
+
+    #[test]
+    fn user_defined_words_are_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo dup ;"), Ok(()));
+        assert_eq!(f.eval("1 FOO Foo foo"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn definitions_are_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": SWAP DUP Dup dup ;"), Ok(()));
+        assert_eq!(f.eval("1 swap"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn redefining_a_built_in_operator() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": + * ;"), Ok(()));
+        assert_eq!(f.eval("3 4 +"), Ok(()));
+        assert_eq!(f.stack(), vec![12]);
+    }
+
+    #[test]
+    fn can_define_variable() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 5 ;"), Ok(()));
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![5]);
+    }
+
+    #[test]
+    fn can_use_different_words_with_the_same_name() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 5 ;"), Ok(()));
+        assert_eq!(f.eval(": bar foo ;"), Ok(()));
+        assert_eq!(f.eval(": foo 6 ;"), Ok(()));
+        assert_eq!(f.eval("bar foo"), Ok(()));
+        assert_eq!(f.stack(), vec![5, 6]);
+    }
+
+    #[test]
+    fn can_define_word_that_uses_word_with_the_same_name() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 10 ;"), Ok(()));
+        assert_eq!(f.eval(": foo foo 1 + ;"), Ok(()));
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![11]);
+    }
+
+    #[test]
+    fn defining_a_number() {
+        let mut f = Forth::new();
+        let result = f.eval(": 1 2 ;");
+        assert!(matches!(result, Err(ForthError::InvalidWord(_))));
+    }
+
+    #[test]
+    fn unterminated_word_definition() {
+        let mut f = Forth::new();
+        assert_eq!(Ok(()), f.eval(":"));
+        assert!(f.is_compiling());
+        assert_eq!(Ok(()), f.eval("foo 1"));
+        assert!(f.is_compiling());
+        assert_eq!(Ok(()), f.eval(";"));
+        assert!(!f.is_compiling());
+        assert_eq!(Ok(()), f.eval("foo"));
+        assert_eq!(f.stack(), vec![1]);
+    }
+
+    #[test]
+    fn definitions_continue_across_lines() {
+        let mut f = Forth::new();
+        assert_eq!(f.prompt(), "> ");
+        assert_eq!(f.eval("1 : add-one ( n -- n )"), Ok(()));
+        assert_eq!(f.prompt(), "] ");
+        assert_eq!(f.eval("  1 +"), Ok(()));
+        assert_eq!(f.eval("; 41 add-one"), Ok(()));
+        assert_eq!(f.prompt(), "> ");
+        assert_eq!(f.stack(), vec![1, 42]);
+    }
+
+    #[test]
+    fn control_flow_across_lines() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": count-down ( n -- )"), Ok(()));
+        assert_eq!(f.eval("  begin dup while"), Ok(()));
+        assert_eq!(f.eval("    1 -"), Ok(()));
+        assert_eq!(f.eval("  repeat ;"), Ok(()));
+        assert_eq!(f.eval("3 count-down"), Ok(()));
+        assert_eq!(f.stack(), vec![0]);
+    }
+
+    #[test]
+    fn errors_end_compile_mode() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo"), Ok(()));
+        assert_eq!(
+            f.eval("then ;"),
+            Err(ForthError::ControlMismatch("THEN without IF".to_string()))
+        );
+        assert!(!f.is_compiling());
+        assert_eq!(f.eval("1 0 / : foo"), Err(ForthError::DivisionByZero));
+        assert!(!f.is_compiling());
+    }
+
+    #[test]
+    fn calling_non_existing_word() {
+        let mut f = Forth::new();
+        assert_eq!(
+            Err(ForthError::UnknownWord("foo".to_string())),
+            f.eval("1 foo")
+        );
+    }
+
+    #[test]
+    fn multiple_definitions() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": one 1 ; : two 2 ; one two +"), Ok(()));
+        assert_eq!(f.stack(), vec![3]);
+    }
+
+    #[test]
+    fn definitions_after_ops() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 + : addone 1 + ; addone"), Ok(()));
+        assert_eq!(f.stack(), vec![4]);
+    }
+
+    #[test]
+    fn redefine_an_existing_word_with_another_existing_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 5 ;"), Ok(()));
+        assert_eq!(f.eval(": bar foo ;"), Ok(()));
+        assert_eq!(f.eval(": foo 6 ;"), Ok(()));
+        assert_eq!(f.eval(": bar foo ;"), Ok(()));
+        assert_eq!(f.eval("bar foo"), Ok(()));
+        assert_eq!(f.stack(), vec![6, 6]);
+    }
+
+    // Conditionals
+
+    #[test]
+    fn if_then_taken() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo if 1 then 2 ;"), Ok(()));
+        assert_eq!(f.eval("-1 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2]);
+    }
+
+    #[test]
+    fn if_then_not_taken() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo if 1 then 2 ;"), Ok(()));
+        assert_eq!(f.eval("0 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![2]);
+    }
+
+    #[test]
+    fn if_else_then() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": choose if 1 else 2 then 3 ;"), Ok(()));
+        assert_eq!(f.eval("5 choose 0 choose"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 3, 2, 3]);
+    }
+
+    #[test]
+    fn nested_if() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": classify if if 11 else 10 then else if 1 else 0 then then ;"),
+            Ok(())
+        );
+        assert_eq!(
+            f.eval("1 1 classify 0 1 classify 1 0 classify 0 0 classify"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![11, 10, 1, 0]);
+    }
+
+    #[test]
+    fn control_words_are_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo IF 1 Else 2 THEN ;"), Ok(()));
+        assert_eq!(f.eval("0 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![2]);
+    }
+
+    #[test]
+    fn if_error() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo if 1 then ;"), Ok(()));
+        assert_eq!(f.eval("foo"), Err(ForthError::StackUnderflow));
+    }
+
+    #[test]
+    fn mismatched_conditionals() {
+        let mut f = Forth::new();
+        assert!(matches!(
+            f.eval(": foo if 1 ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo 1 then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo else 1 then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo if 1 else 2 else 3 then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn conditionals_are_compile_only() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("1 if"),
+            Err(ForthError::CompileOnly("if".to_string()))
+        );
+    }
+
+    // Counted loops
+
+    #[test]
+    fn do_loop() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 5 0 do i loop ;"), Ok(()));
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn do_loop_runs_at_least_once() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 0 0 do i leave loop ;"), Ok(()));
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0]);
+    }
+
+    #[test]
+    fn question_do_skips_empty_range() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 7 swap 0 ?do i loop ;"), Ok(()));
+        assert_eq!(f.eval("0 foo 2 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![7, 7, 0, 1]);
+    }
+
+    #[test]
+    fn plus_loop() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": evens 10 0 do i 2 +loop ;"), Ok(()));
+        assert_eq!(f.eval("evens"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn plus_loop_counting_down() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": down 0 3 do i -1 +loop ;"), Ok(()));
+        assert_eq!(f.eval("down"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn nested_loops_with_i_and_j() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": grid 2 0 do 2 0 do j i loop loop ;"), Ok(()));
+        assert_eq!(f.eval("grid"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 0, 0, 1, 1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn leave_exits_innermost_loop() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo 2 0 do 10 0 do i dup 2 - if else leave then loop loop ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn unloop_before_exit() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": find-three 10 0 do i 3 - if else i unloop exit then loop -1 ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("4 find-three"), Ok(()));
+        assert_eq!(f.stack(), vec![4, 3]);
+    }
+
+    #[test]
+    fn loop_index_outside_loop() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("i"), Err(ForthError::ReturnStackUnderflow));
+        assert_eq!(f.eval("1 0 j"), Err(ForthError::ReturnStackUnderflow));
+    }
+
+    #[test]
+    fn mismatched_loops() {
+        let mut f = Forth::new();
+        assert!(matches!(
+            f.eval(": foo 1 0 do ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo loop ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo leave ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo 1 0 do if loop then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo if 1 0 do then loop ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+    }
+
+    // Indefinite loops
+
+    #[test]
+    fn begin_until() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": not if 0 else -1 then ;"), Ok(()));
+        assert_eq!(f.eval(": countdown begin dup 1 - dup not until ;"), Ok(()));
+        assert_eq!(f.eval("3 countdown"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn begin_while_repeat() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": countdown begin dup while dup 1 - repeat ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("2 countdown"), Ok(()));
+        assert_eq!(f.stack(), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn begin_while_repeat_skips_body() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo begin dup while 99 repeat ;"), Ok(()));
+        assert_eq!(f.eval("0 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0]);
+    }
+
+    #[test]
+    fn begin_again_with_exit() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo begin 1 - dup if else exit then again ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("5 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0]);
+    }
+
+    #[test]
+    fn loops_nest_with_conditionals() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo begin dup while dup 2 mod if 10 swap then 1 - repeat drop ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("4 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![10, 10]);
+    }
+
+    #[test]
+    fn nested_begin_loops() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo begin dup while 2 begin dup while 1 - repeat drop 1 - repeat ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("3 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![0]);
+    }
+
+    #[test]
+    fn mismatched_indefinite_loops() {
+        let mut f = Forth::new();
+        assert!(matches!(
+            f.eval(": foo begin 1 ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo 1 until ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo again ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo begin repeat ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo 1 while ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo begin if until then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": foo begin 1 while until ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn mismatch_is_reported_at_definition_time() {
+        let mut f = Forth::new();
+        assert!(matches!(
+            f.eval(": foo begin 1 while ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert_eq!(
+            f.eval("foo"),
+            Err(ForthError::UnknownWord("foo".to_string()))
+        );
+    }
+
+    // Return stack
+
+    #[test]
+    fn to_r_and_r_from() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 >r 3 r>"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 3, 2]);
+        assert!(f.return_stack().is_empty());
+    }
+
+    #[test]
+    fn r_fetch_leaves_return_stack() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("5 >r r@ r@"), Ok(()));
+        assert_eq!(f.stack(), vec![5, 5]);
+        assert_eq!(f.return_stack(), vec![5]);
+    }
+
+    #[test]
+    fn two_to_r_and_two_r_from() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 2>r 3 2r@ 2r>"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 1, 2, 1, 2]);
+        assert!(f.return_stack().is_empty());
+    }
+
+    #[test]
+    fn rdrop() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 >r >r rdrop r>"), Ok(()));
+        assert_eq!(f.stack(), vec![2]);
+    }
+
+    #[test]
+    fn return_stack_in_definitions() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": keep-top >r + r> ;"), Ok(()));
+        assert_eq!(f.eval("1 2 3 keep-top"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 3]);
+    }
+
+    #[test]
+    fn return_stack_words_are_case_insensitive() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 >R R@ R> 2 3 2>R 2R>"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn return_stack_underflow() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("r>"), Err(ForthError::ReturnStackUnderflow));
+        assert_eq!(f.eval("r@"), Err(ForthError::ReturnStackUnderflow));
+        assert_eq!(f.eval("rdrop"), Err(ForthError::ReturnStackUnderflow));
+        assert_eq!(f.eval("1 >r 2r>"), Err(ForthError::ReturnStackUnderflow));
+        assert_eq!(f.eval(">r"), Err(ForthError::StackUnderflow));
+    }
+
+    // Comparison and logic
+
+    #[test]
+    fn comparisons_return_flags() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 1 = 1 2 = 1 2 <> 2 2 <>"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0, -1, 0]);
+    }
+
+    #[test]
+    fn less_and_greater() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 < 2 1 < 1 2 > 2 1 > -3 2 <"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0, 0, -1, -1]);
+    }
+
+    #[test]
+    fn zero_comparisons() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("0 0= 5 0= -5 0< 5 0< 5 0> 0 0>"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0, -1, 0, -1, 0]);
+    }
+
+    #[test]
+    fn logic_words() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("12 10 and 12 10 or 12 10 xor 0 invert -1 invert"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![8, 14, 6, -1, 0]);
+    }
+
+    #[test]
+    fn flags_combine_with_logic_words() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 1 = 1 2 = or 1 1 = 1 2 = and"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0]);
+    }
+
+    #[test]
+    fn negate_abs_min_max() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("5 negate -5 abs 3 7 min 3 7 max -3 -7 max"), Ok(()));
+        assert_eq!(f.stack(), vec![-5, 5, 3, 7, -3]);
+    }
+
+    #[test]
+    fn shifts() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 4 lshift 256 4 rshift 1 64 lshift"), Ok(()));
+        assert_eq!(f.stack(), vec![16, 16, 0]);
+    }
+
+    #[test]
+    fn rshift_is_logical() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("-1 60 rshift"), Ok(()));
+        assert_eq!(f.stack(), vec![15]);
+    }
+
+    #[test]
+    fn comparisons_drive_conditionals() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": sign dup 0< if drop -1 else 0> if 1 else 0 then then ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("-7 sign 0 sign 7 sign"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0, 1]);
+    }
+
+    #[test]
+    fn comparison_errors() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 ="), Err(ForthError::StackUnderflow));
+        assert_eq!(f.eval("0="), Err(ForthError::StackUnderflow));
+        assert_eq!(f.eval("invert"), Err(ForthError::StackUnderflow));
+    }
+
+    // Integer cells
+
+    #[test]
+    fn non_integer_literals_are_not_cells() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("2.5"),
+            Err(ForthError::UnknownWord("2.5".to_string()))
+        );
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("9223372036854775807 1 + -9223372036854775808 1 -"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![i64::MIN, i64::MAX]);
+    }
+
+    #[test]
+    fn division_is_floored() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("7 2 / -7 2 / 7 -2 / -7 -2 /"), Ok(()));
+        assert_eq!(f.stack(), vec![3, -4, -4, 3]);
+    }
+
+    #[test]
+    fn mod_takes_sign_of_divisor() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("7 2 mod -7 2 mod 7 -2 mod -7 -2 mod"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1, -1, -1]);
+    }
+
+    #[test]
+    fn slash_mod() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("-7 2 /mod"), Ok(()));
+        assert_eq!(f.stack(), vec![1, -4]);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 0 /"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1 0 mod"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1 0 /mod"), Err(ForthError::DivisionByZero));
+    }
+
+    #[test]
+    fn division_overflow_wraps() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("-9223372036854775808 -1 /"), Ok(()));
+        assert_eq!(f.stack(), vec![i64::MIN]);
+    }
+
+    #[test]
+    fn plus_loop_terminates_near_cell_limits() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo 9223372036854775807 9223372036854775805 do i loop ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(f.stack(), vec![i64::MAX - 2, i64::MAX - 1]);
+    }
+
+    // Floating point
+
+    #[test]
+    fn float_literals() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1.5e0 1e 2E2 -2.5e-1 .5e1 3.e0"), Ok(()));
+        assert_eq!(f.float_stack(), vec![1.5, 1.0, 200.0, -0.25, 5.0, 3.0]);
+        assert!(f.stack().is_empty());
+    }
+
+    #[test]
+    fn float_literals_require_exponent() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("1.5"),
+            Err(ForthError::UnknownWord("1.5".to_string()))
+        );
+        assert_eq!(f.eval("e5"), Err(ForthError::UnknownWord("e5".to_string())));
+        assert_eq!(
+            f.eval("1e5x"),
+            Err(ForthError::UnknownWord("1e5x".to_string()))
+        );
+        assert_eq!(
+            f.eval("1.2.3e0"),
+            Err(ForthError::UnknownWord("1.2.3e0".to_string()))
+        );
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1.5e0 2e0 f+ 1e0 f- 3e0 f* 2e0 f/"), Ok(()));
+        assert_eq!(f.float_stack(), vec![3.75]);
+    }
+
+    #[test]
+    fn float_stack_is_separate() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2e0 3 4e0 fswap"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 3]);
+        assert_eq!(f.float_stack(), vec![4.0, 2.0]);
+    }
+
+    #[test]
+    fn float_stack_words() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1e 2e fover fdup 3e frot fdrop"), Ok(()));
+        assert_eq!(f.float_stack(), vec![1.0, 2.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn float_functions() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("16e fsqrt 0e fsin 0e fcos 0e fexp 1e fln -2e fabs 2e fnegate"),
+            Ok(())
+        );
+        assert_eq!(f.float_stack(), vec![4.0, 0.0, 1.0, 1.0, 0.0, 2.0, -2.0]);
+    }
+
+    #[test]
+    fn float_comparisons() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1e 2e f< 2e 1e f< 0e f0= 1e f0= -1e f0<"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0, -1, 0, -1]);
+        assert!(f.float_stack().is_empty());
+    }
+
+    #[test]
+    fn float_conversion() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("7 s>f 2e f/ f>s -7 s>f 2e f/ f>s"), Ok(()));
+        assert_eq!(f.stack(), vec![3, -3]);
+    }
+
+    #[test]
+    fn float_words_in_definitions() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": hypot fdup f* fswap fdup f* f+ fsqrt ;"), Ok(()));
+        assert_eq!(f.eval("3e 4e hypot"), Ok(()));
+        assert_eq!(f.float_stack(), vec![5.0]);
+    }
+
+    #[test]
+    fn float_stack_underflow() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("f+"), Err(ForthError::FloatStackUnderflow));
+        assert_eq!(f.eval("1e f."), Ok(()));
+        assert_eq!(f.eval("f>s"), Err(ForthError::FloatStackUnderflow));
+        assert_eq!(f.eval("s>f"), Err(ForthError::StackUnderflow));
+    }
+
+    #[test]
+    fn formats_floats() {
+        assert_eq!(format_float(3.0), "3.");
+        assert_eq!(format_float(-0.25), "-0.25");
+        assert_eq!(format_float(f64::INFINITY), "inf");
+    }
+
+    // Double cells
+
+    #[test]
+    fn double_literals() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1234. -1. 18446744073709551616."), Ok(()));
+        assert_eq!(f.stack(), vec![1234, 0, -1, -1, 0, 1]);
+    }
+
+    #[test]
+    fn double_literal_needs_digits() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("-."), Err(ForthError::UnknownWord("-.".to_string())));
+        assert_eq!(
+            f.eval("1.2."),
+            Err(ForthError::UnknownWord("1.2.".to_string()))
+        );
+    }
+
+    #[test]
+    fn double_arithmetic() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("18446744073709551615. 1. d+ 1. d-"), Ok(()));
+        assert_eq!(f.stack(), vec![-1, 0]);
+    }
+
+    #[test]
+    fn double_comparisons() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("1. 2. d< 2. 1. d< -1. 1. d< 5. 5. d= 0. d0="),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![-1, 0, -1, -1, -1]);
+    }
+
+    #[test]
+    fn dnegate_and_dabs() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("5. dnegate -5. dabs"), Ok(()));
+        assert_eq!(f.stack(), vec![-5, -1, 5, 0]);
+    }
+
+    #[test]
+    fn single_to_double_conversion() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("-3 s>d 7. d>s"), Ok(()));
+        assert_eq!(f.stack(), vec![-3, -1, 7]);
+    }
+
+    #[test]
+    fn mixed_multiplication() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("9223372036854775807 2 m* -1 -1 um*"), Ok(()));
+        assert_eq!(f.stack(), vec![-2, 0, 1, -2]);
+    }
+
+    #[test]
+    fn um_slash_mod() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("18446744073709551616. 3 um/mod"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 6148914691236517205]);
+    }
+
+    #[test]
+    fn symmetric_and_floored_division() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("-7. 2 sm/rem -7. 2 fm/mod 7. -2 sm/rem 7. -2 fm/mod"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![-1, -3, 1, -4, 1, -3, -1, -4]);
+    }
+
+    #[test]
+    fn star_slash_uses_double_intermediate() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("9223372036854775807 10 100 */ 1000 3 7 */mod"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![922337203685477580, 4, 428]);
+    }
+
+    #[test]
+    fn double_division_by_zero() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1. 0 um/mod"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1. 0 sm/rem"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1. 0 fm/mod"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1 1 0 */"), Err(ForthError::DivisionByZero));
+    }
+
+    #[test]
+    fn double_underflow() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 d+"), Err(ForthError::StackUnderflow));
+        assert_eq!(f.eval("d."), Err(ForthError::StackUnderflow));
+    }
+
+    // Data space
+
+    #[test]
+    fn here_and_allot() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here 3 cells allot here swap -"), Ok(()));
+        assert_eq!(f.stack(), vec![24]);
+    }
+
+    #[test]
+    fn comma_compiles_cells() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here 7 , 8 , dup @ swap cell+ @"), Ok(()));
+        assert_eq!(f.stack(), vec![7, 8]);
+    }
+
+    #[test]
+    fn characters() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("here 65 c, 300 c, dup c@ swap char+ c@ 3 chars"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![65, 44, 3]);
+    }
+
+    #[test]
+    fn store_and_plus_store() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here 1 cells allot -5 over ! 7 over +! @"), Ok(()));
+        assert_eq!(f.stack(), vec![2]);
+    }
+
+    #[test]
+    fn c_store() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here 0 , 258 over c! @"), Ok(()));
+        assert_eq!(f.stack(), vec![2]);
+    }
+
+    #[test]
+    fn float_memory() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("here 2 floats allot 1.5e0 dup f! 2.5e0 dup float+ f! dup f@ float+ f@"),
+            Ok(())
+        );
+        assert_eq!(f.float_stack(), vec![1.5, 2.5]);
+    }
+
+    #[test]
+    fn out_of_bounds_access() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here @"), Err(ForthError::InvalidAddress(0)));
+        assert_eq!(f.eval("-8 @"), Err(ForthError::InvalidAddress(-8)));
+        assert_eq!(f.eval("4 allot 1 0 !"), Err(ForthError::InvalidAddress(0)));
+        assert_eq!(f.eval("1 4 c!"), Err(ForthError::InvalidAddress(4)));
+        assert_eq!(f.eval("-5 allot"), Err(ForthError::InvalidAddress(-1)));
+    }
+
+    #[test]
+    fn variable() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("variable x x @ 42 x ! x @"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 42]);
+    }
+
+    #[test]
+    fn variables_are_distinct() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("variable a variable b 1 a ! 2 b ! a @ b @"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2]);
+    }
+
+    #[test]
+    fn variable_in_definition() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("variable counter : bump 1 counter +! ;"), Ok(()));
+        assert_eq!(f.eval("bump bump bump counter @"), Ok(()));
+        assert_eq!(f.stack(), vec![3]);
+    }
+
+    #[test]
+    fn constant() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("10 constant ten : twenty ten 2 * ; twenty TEN"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![20, 10]);
+    }
+
+    #[test]
+    fn value_and_to() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("5 value v v 7 to v v"), Ok(()));
+        assert_eq!(f.stack(), vec![5, 7]);
+    }
+
+    #[test]
+    fn to_in_definition() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("0 value total : add-to total + to total ;"), Ok(()));
+        assert_eq!(f.eval("3 add-to 4 add-to total"), Ok(()));
+        assert_eq!(f.stack(), vec![7]);
+    }
+
+    #[test]
+    fn to_requires_value() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("variable x"), Ok(()));
+        assert_eq!(
+            f.eval("1 to x"),
+            Err(ForthError::InvalidWord("x".to_string()))
+        );
+        assert_eq!(
+            f.eval(": foo to x ;"),
+            Err(ForthError::InvalidWord("x".to_string()))
+        );
+        assert_eq!(f.eval(": foo to ;"), Err(ForthError::Unterminated));
+    }
+
+    #[test]
+    fn defining_words_need_a_name() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("variable"), Err(ForthError::Unterminated));
+        assert!(matches!(
+            f.eval("1 constant 2"),
+            Err(ForthError::InvalidWord(_))
+        ));
+    }
+
+    #[test]
+    fn defining_word_parses_name_at_run_time() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": var variable ; var y 9 y ! y @"), Ok(()));
+        assert_eq!(f.stack(), vec![9]);
+    }
+
+    // Defining words
+
+    #[test]
+    fn create_pushes_data_field() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("here create foo foo ="), Ok(()));
+        assert_eq!(f.stack(), vec![-1]);
+    }
+
+    #[test]
+    fn create_with_comma() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("create table 10 , 20 , 30 , table 2 cells + @"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![30]);
+    }
+
+    #[test]
+    fn create_does_array() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": array create cells allot does> swap cells + ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("10 array foo 5 array bar"), Ok(()));
+        assert_eq!(f.eval("42 3 foo ! 7 3 bar ! 3 foo @ 3 bar @"), Ok(()));
+        assert_eq!(f.stack(), vec![42, 7]);
+    }
+
+    #[test]
+    fn does_stops_the_defining_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": maker create 1 , does> @ 100 ;"), Ok(()));
+        assert_eq!(f.eval("maker one"), Ok(()));
+        assert!(f.stack().is_empty());
+        assert_eq!(f.eval("one"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 100]);
+    }
+
+    #[test]
+    fn does_with_control_flow() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": flag-word create , does> @ if 1 else 0 then ;"),
+            Ok(())
+        );
+        assert_eq!(f.eval("-1 flag-word yes 0 flag-word no yes no"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 0]);
+    }
+
+    #[test]
+    fn enumeration() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": enum dup constant 1 + ;"), Ok(()));
+        assert_eq!(f.eval("0 enum red enum green enum blue drop"), Ok(()));
+        assert_eq!(f.eval("red green blue"), Ok(()));
+        assert_eq!(f.stack(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn created_words_in_definitions() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": counter create 0 , does> dup @ 1 + dup rot ! ;"),
+            Ok(())
+        );
+        assert_eq!(
+            f.eval("counter ticks : tick ticks drop ; tick tick ticks"),
+            Ok(())
+        );
+        assert_eq!(f.stack(), vec![3]);
+    }
+
+    #[test]
+    fn does_requires_created_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": bad does> 1 ;"), Ok(()));
+        assert_eq!(
+            f.eval("bad"),
+            Err(ForthError::InvalidWord("bad".to_string()))
+        );
+        assert_eq!(
+            f.eval("does>"),
+            Err(ForthError::CompileOnly("does>".to_string()))
+        );
+    }
+
+    #[test]
+    fn does_inside_control_structure() {
+        let mut f = Forth::new();
+        assert!(matches!(
+            f.eval(": bad create if does> 1 then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert!(matches!(
+            f.eval(": bad create does> if 1 ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+    }
+
+    // Lexing
+
+    fn texts(lexemes: &[Lexeme]) -> Vec<&str> {
+        lexemes.iter().map(|lexeme| lexeme.text.as_str()).collect()
+    }
+
+    #[test]
+    fn lexes_any_whitespace() {
+        let forth = Forth::new();
+        let lexemes = forth.state.lex("  1\t2  \r\n3\n\n  dup  ").unwrap();
+        assert_eq!(texts(&lexemes), vec!["1", "2", "3", "dup"]);
+    }
+
+    #[test]
+    fn lexes_spans() {
+        let forth = Forth::new();
+        let lexemes = forth.state.lex("1 dup\n  swap").unwrap();
+        let spans: Vec<Span> = lexemes.iter().map(|lexeme| lexeme.span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    line: 1,
+                    column: 1,
+                    len: 1
+                },
+                Span {
+                    line: 1,
+                    column: 3,
+                    len: 3
+                },
+                Span {
+                    line: 2,
+                    column: 3,
+                    len: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_count_characters() {
+        let forth = Forth::new();
+        let lexemes = forth.state.lex("é ünïcödé x").unwrap();
+        assert_eq!(
+            lexemes[1].span,
+            Span {
+                line: 1,
+                column: 3,
+                len: 7
+            }
+        );
+        assert_eq!(
+            lexemes[2].span,
+            Span {
+                line: 1,
+                column: 11,
+                len: 1
+            }
+        );
+    }
+
+    #[test]
+    fn extra_whitespace_is_ignored() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1  2\t\t+ "), Ok(()));
+        assert_eq!(f.stack(), vec![3]);
+        assert_eq!(f.eval("   "), Ok(()));
+    }
+
+    #[test]
+    fn definitions_span_lines() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo\n  dup\n  + ;\n2 foo"), Ok(()));
+        assert_eq!(f.stack(), vec![4]);
+    }
+
+    #[test]
+    fn error_points_at_unknown_word() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("1 2\n  + nope"),
+            Err(ForthError::UnknownWord("nope".to_string()))
+        );
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 2,
+                column: 5,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn error_points_at_failing_word() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo drop drop ;"), Ok(()));
+        assert_eq!(f.eval("1 dup foo foo"), Err(ForthError::StackUnderflow));
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 1,
+                column: 11,
+                len: 3
+            })
+        );
+    }
+
+    #[test]
+    fn error_points_into_definition() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo 1 bar ;"),
+            Err(ForthError::UnknownWord("bar".to_string()))
+        );
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 1,
+                column: 9,
+                len: 3
+            })
+        );
+        assert!(matches!(
+            f.eval(": foo 1 then ;"),
+            Err(ForthError::ControlMismatch(_))
+        ));
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 1,
+                column: 9,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn unresolved_control_names_the_open_word() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval(": foo 1 if 2 ;"),
+            Err(ForthError::ControlMismatch("IF without THEN".to_string()))
+        );
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 1,
+                column: 9,
+                len: 2
+            })
+        );
+        assert_eq!(
+            f.eval(": foo begin 1 while ;"),
+            Err(ForthError::ControlMismatch(
+                "BEGIN without UNTIL, AGAIN or REPEAT".to_string()
+            ))
+        );
+        assert_eq!(
+            f.eval(": foo 0 0 ?do ;"),
+            Err(ForthError::ControlMismatch("?DO without LOOP".to_string()))
+        );
+    }
+
+    #[test]
+    fn error_spans_only_point_into_the_current_input() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo 1 nope"), Ok(()));
+        assert_eq!(
+            f.eval(";"),
+            Err(ForthError::UnknownWord("nope".to_string()))
+        );
+        assert_eq!(f.error_span(), None);
+        assert_eq!(f.eval(": bar 1 if"), Ok(()));
+        assert!(matches!(f.eval("2 ;"), Err(ForthError::ControlMismatch(_))));
+        assert_eq!(f.error_span(), None);
+        assert_eq!(f.eval(": baz 1"), Ok(()));
+        assert_eq!(
+            f.eval("nope ;"),
+            Err(ForthError::UnknownWord("nope".to_string()))
+        );
+        assert_eq!(
+            f.error_span(),
+            Some(Span {
+                line: 1,
+                column: 1,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn error_span_is_cleared() {
+        let mut f = Forth::new();
+        assert!(f.eval("nope").is_err());
+        assert_eq!(f.eval("1"), Ok(()));
+        assert_eq!(f.error_span(), None);
     }
 
-    pub fn prompt(&self) -> String {
-        "> ".to_string()
-    }
+    // Comments
 
-    #[cfg(test)]
-    pub fn stack(&self) -> &[f64] {
-        &self.state.stack
+    #[test]
+    fn backslash_comments_run_to_end_of_line() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 2 \\ 3 4\n5 \\"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 2, 5]);
     }
 
-    pub fn eval(&mut self, input: &str) -> Result<Option<f64>, ForthError> {
-        let line = input.trim().to_string();
-        if line.is_empty() {
-            Ok(None)
-        } else {
-            let lexemes = self.lex(&line)?;
-            let tokens = self.tokenize(&lexemes)?;
-            let result = self.run(&tokens)?;
+    #[test]
+    fn paren_comments() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": sq ( n -- n*n ) dup * ; 3 sq"), Ok(()));
+        assert_eq!(f.stack(), vec![9]);
+    }
 
-            Ok(result)
-        }
+    #[test]
+    fn paren_comments_span_lines() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 ( two\nthree\n) 4"), Ok(()));
+        assert_eq!(f.stack(), vec![1, 4]);
     }
 
-    fn run(&mut self, tokens: &[Token]) -> Result<Option<f64>, ForthError> {
-        let mut result = None;
+    #[test]
+    fn paren_comment_ends_at_any_paren() {
+        let mut f = Forth::new();
+        assert_eq!(
+            f.eval("1 ( a b) 2 (c) 3"),
+            Err(ForthError::UnknownWord("(c)".to_string()))
+        );
+        assert_eq!(f.stack(), vec![1, 2]);
+    }
 
-        self.state.push_tokens(tokens);
+    #[test]
+    fn unclosed_comment_runs_to_end_of_input() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("1 ( 2 3"), Ok(()));
+        assert_eq!(f.stack(), vec![1]);
+    }
 
-        while let Some(token) = self.next_token() {
-            result = token.eval(&mut self.state)?;
-            if let Some(num) = result {
-                self.state.push(num);
+    #[test]
+    fn comment_spans_keep_positions() {
+        let forth = Forth::new();
+        let lexemes = forth.state.lex("( a\nb ) dup").unwrap();
+        assert_eq!(texts(&lexemes), vec!["dup"]);
+        assert_eq!(
+            lexemes[0].span,
+            Span {
+                line: 2,
+                column: 5,
+                len: 3
             }
-        }
-
-        Ok(result)
+        );
     }
 
-    fn lex(&self, input: &str) -> Result<Vec<String>, ForthError> {
-        Ok(input.split(' ').map(|s| s.to_string()).collect())
+    #[test]
+    fn dot_paren_is_parsed() {
+        let mut forth = Forth::new();
+        let lexemes = forth.state.lex(".( hello  world) 1").unwrap();
+        let tokens = forth.state.tokenize(&lexemes).map(|(tokens, _)| tokens);
+        assert_eq!(
+            tokens,
+            Ok(vec![
+                Token::Print("hello  world".to_string()),
+                Token::Number(1)
+            ])
+        );
     }
 
-    fn tokenize(&self, input: &[String]) -> Result<Vec<Token>, ForthError> {
-        let mut tokens = Vec::new();
-
-        let mut user_defined = Vec::new();
-        let mut in_user_defined = false;
+    #[test]
+    fn definitions_compile_when_reached() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval("1 . : f .( x) ; 2 ."), Ok(()));
+        assert_eq!(*output.borrow(), "1 x2 ");
+        assert_eq!(
+            f.eval("3\n: g then ;"),
+            Err(ForthError::ControlMismatch("THEN without IF".to_string()))
+        );
+        assert_eq!(f.stack(), [3]);
+    }
 
-        for item in input {
-            if item == ":" {
-                in_user_defined = true;
-                continue;
-            }
+    #[test]
+    fn dot_paren_is_immediate_in_definitions() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": foo .( compiling foo) 1 ; foo"), Ok(()));
+        assert_eq!(f.stack(), vec![1]);
+    }
 
-            let token = if let Ok(value) = item.parse() {
-                Token::Number(value)
-            } else if item == ";" {
-                in_user_defined = false;
-                Token::UserDefined(user_defined.clone())
-            } else {
-                Token::Word(item.clone())
-            };
-            if in_user_defined {
-                user_defined.push(token);
-            } else {
-                tokens.push(token);
-            }
-            if !in_user_defined && !user_defined.is_empty() {
-                user_defined.clear();
-            }
-        }
+    // Strings
 
-        if in_user_defined {
-            Err(ForthError::Unterminated)
-        } else {
-            Ok(tokens)
-        }
+    fn string_at(f: &Forth, address: i64, len: i64) -> String {
+        let bytes = f.state.bytes(address, len as usize).unwrap();
+        String::from_utf8_lossy(bytes).into_owned()
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
 
     #[test]
-    fn cannot_parse_letter() {
+    fn dot_quote_keeps_spacing() {
         let mut forth = Forth::new();
+        let lexemes = forth.state.lex(".\" a  b 1 2\" 3").unwrap();
+        let tokens = forth.state.tokenize(&lexemes).map(|(tokens, _)| tokens);
         assert_eq!(
-            forth.eval("1 a 3 4 5"),
-            Err(ForthError::UnknownWord("a".to_string()))
+            tokens,
+            Ok(vec![Token::Print("a  b 1 2".to_string()), Token::Number(3)])
         );
     }
 
     #[test]
-    fn parses_numbers() {
-        let mut forth = Forth::new();
-        assert_eq!(forth.eval("1 2.3 0.3 4 5"), Ok(Some(5.0)));
+    fn dot_quote_in_definition() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": greet .\" hello 42\" 1 ; greet"), Ok(()));
+        assert_eq!(f.stack(), vec![1]);
     }
 
     #[test]
-    fn parses_math_expressions() {
-        let forth = Forth::new();
-        let lexemes = forth.lex("1 2.3 + 0.3 * 4 / 5 -").unwrap();
-        let result = forth.tokenize(&lexemes);
-        assert_eq!(
-            Ok(vec![
-                Token::Number(1.0),
-                Token::Number(2.3),
-                Token::Word("+".to_string()),
-                Token::Number(0.3),
-                Token::Word("*".to_string()),
-                Token::Number(4.0),
-                Token::Word("/".to_string()),
-                Token::Number(5.0),
-                Token::Word("-".to_string()),
-            ]),
-            result
-        );
+    fn s_quote_pushes_address_and_length() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval("s\" hi  there\""), Ok(()));
+        let stack = f.stack();
+        assert_eq!(stack[1], 9);
+        assert_eq!(string_at(&f, stack[0], stack[1]), "hi  there");
     }
 
     #[test]
-    fn simple_addition_works() {
-        let mut forth = Forth::new();
-        let lexemes = forth.lex("5 6 +").unwrap();
-        let tokens = forth.tokenize(&lexemes).unwrap();
-        let result = forth.run(&tokens).unwrap();
-        assert_eq!(None, result);
+    fn s_quote_in_definition_is_stored_once() {
+        let mut f = Forth::new();
+        assert_eq!(f.eval(": name s\" forth\" ; here name name here"), Ok(()));
+        let stack = f.stack();
+        assert_eq!(stack[0], 5);
+        assert_eq!(stack[5], 5);
+        assert_eq!(stack[1], stack[3]);
+        assert_eq!(string_at(&f, stack[1], stack[2]), "forth");
     }
 
     #[test]
-    fn dup() {
+    fn c_quote_and_count() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0],);
+        assert_eq!(f.eval(": name c\" abc\" ; name count"), Ok(()));
+        let stack = f.stack();
+        assert_eq!(stack[1], 3);
+        assert_eq!(string_at(&f, stack[0], stack[1]), "abc");
     }
 
     #[test]
-    fn dup_top_value_only() {
+    fn counted_strings_are_limited() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 2.0]);
+        let input = format!("c\" {}\"", "x".repeat(256));
+        assert_eq!(f.eval(&input), Err(ForthError::StringTooLong(256)));
     }
 
     #[test]
-    fn dup_case_insensitive() {
+    fn s_backslash_quote_escapes() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 DUP Dup dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(f.eval("s\\\" a\\tb\\q\\\"\\x41\\n\\\\\""), Ok(()));
+        let stack = f.stack();
+        assert_eq!(string_at(&f, stack[0], stack[1]), "a\tb\"\"A\n\\");
     }
 
     #[test]
-    fn dup_error() {
+    fn s_backslash_quote_hex_escapes_are_bytes() {
         let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("dup"));
+        assert_eq!(f.eval("s\\\" \\xff\\x80é\""), Ok(()));
+        let stack = f.stack();
+        assert_eq!(stack[1], 4);
+        let bytes = f.state.bytes(stack[0], 4).unwrap();
+        assert_eq!(bytes, [0xff, 0x80, 0xc3, 0xa9]);
     }
 
     #[test]
-    fn two_dup() {
+    fn type_checks_the_range() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 2dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 1.0, 2.0]);
+        assert_eq!(f.eval("s\" abc\" type"), Ok(()));
+        assert_eq!(f.eval("here 1 type"), Err(ForthError::InvalidAddress(3)));
     }
 
+    // Dictionary
+
     #[test]
-    fn two_dup_top_pair_only() {
+    fn words_lists_builtins_and_definitions() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 2dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 3.0, 2.0, 3.0]);
+        assert_eq!(f.eval(": Square dup * ; variable dup"), Ok(()));
+        let words = f.words();
+        for word in &["+", "dup", "if", "s\\\"", ":", "square", "variable"] {
+            assert!(words.contains(&word.to_string()), "missing {}", word);
+        }
+        assert_eq!(words.iter().filter(|word| *word == "dup").count(), 1);
+        assert!(words.windows(2).all(|pair| pair[0] < pair[1]));
     }
 
+    // Command line
+
     #[test]
-    fn two_dup_case_insensitive() {
+    fn shebang_is_a_comment() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 2DUP 2Dup 2dup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0]);
+        assert_eq!(f.eval("#! /usr/bin/env forth\n1 2 +"), Ok(()));
+        assert_eq!(f.eval("#!/usr/bin/forth 3\n4 #! 5\n6"), Ok(()));
+        assert_eq!(f.stack(), vec![3, 4, 6]);
     }
 
     #[test]
-    fn two_dup_error() {
+    fn command_line_arguments() {
         let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("2dup"));
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 2dup"));
+        let args = ["script.fs".to_string(), "hello".to_string()];
+        assert_eq!(f.set_args(&args), Ok(()));
+        assert_eq!(f.eval("argc 1 arg 5 arg"), Ok(()));
+        let stack = f.stack();
+        assert_eq!(stack[0], 2);
+        assert_eq!(string_at(&f, stack[1], stack[2]), "hello");
+        assert_eq!(&stack[3..], &[0, 0]);
+    }
+
+    // Source files
+
+    // A directory of source files, removed when dropped.
+    struct SourceDir(PathBuf);
+
+    impl std::ops::Deref for SourceDir {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl Drop for SourceDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Write source files into a fresh directory.
+    fn source_files(name: &str, files: &[(&str, &str)]) -> SourceDir {
+        let dir = std::env::temp_dir().join(format!("forth-{}-{}", name, std::process::id()));
+        let dir = SourceDir(dir);
+        for (file, text) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
     }
 
     #[test]
-    fn rot() {
+    fn include_resolves_relative_to_including_file() {
+        let dir = source_files(
+            "include",
+            &[
+                ("main.fs", "include lib/square.fs\n3 square"),
+                ("lib/square.fs", "include mul.fs\n: square dup mul ;"),
+                ("lib/mul.fs", ": mul * ;"),
+            ],
+        );
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 rot"), Ok(None));
-        assert_eq!(f.stack(), vec![2.0, 3.0, 1.0]);
+        let main = dir.join("main.fs");
+        assert_eq!(f.include(main.to_str().unwrap()), Ok(()));
+        assert_eq!(f.stack(), vec![9]);
     }
 
     #[test]
-    fn rot_case_insensitive() {
+    fn include_from_included() {
+        let dir = source_files("included", &[("five.fs", "5")]);
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 ROT Rot rot"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 3.0]);
+        let input = format!(
+            ": load s\" {}\" included ; load load",
+            dir.join("five.fs").display()
+        );
+        assert_eq!(f.eval(&input), Ok(()));
+        assert_eq!(f.stack(), vec![5, 5]);
     }
 
     #[test]
-    fn rot_error() {
+    fn require_includes_once() {
+        let dir = source_files(
+            "require",
+            &[
+                ("main.fs", "require one.fs require one.fs include one.fs"),
+                ("one.fs", "1"),
+            ],
+        );
         let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("rot"));
+        let main = dir.join("main.fs");
+        assert_eq!(f.include(main.to_str().unwrap()), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1]);
+        let input = format!("s\" {}\" required", dir.join("one.fs").display());
+        assert_eq!(f.eval(&input), Ok(()));
+        assert_eq!(f.stack(), vec![1, 1]);
     }
 
     #[test]
-    fn drop() {
+    fn include_errors_name_file_and_line() {
+        let dir = source_files(
+            "include-error",
+            &[
+                ("main.fs", "\\ main\ninclude bad.fs"),
+                ("bad.fs", "1\n\n2 nope"),
+            ],
+        );
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 drop"), Ok(None));
-        assert_eq!(Vec::<f64>::new(), f.stack());
+        let main = dir.join("main.fs");
+        assert_eq!(
+            f.include(main.to_str().unwrap()),
+            Err(ForthError::Included {
+                location: format!("{}:2", main.display()),
+                error: Box::new(ForthError::Included {
+                    location: format!("{}:3", dir.join("bad.fs").display()),
+                    error: Box::new(ForthError::UnknownWord("nope".to_string())),
+                }),
+            })
+        );
     }
 
     #[test]
-    fn drop_with_two() {
+    fn include_leaves_definitions_open_around_it() {
+        let dir = source_files("include-compiling", &[("open.fs", ": open 1")]);
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 drop"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0]);
+        let open = dir.join("open.fs");
+        assert_eq!(
+            f.include(open.to_str().unwrap()),
+            Err(ForthError::Included {
+                location: open.display().to_string(),
+                error: Box::new(ForthError::Unterminated),
+            })
+        );
+        assert!(!f.is_compiling());
     }
 
     #[test]
-    fn drop_case_insensitive() {
+    fn include_missing_file() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 4 DROP Drop drop"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0]);
+        match f.eval("include no-such-file.fs") {
+            Err(ForthError::FileError(name, _)) => assert_eq!(name, "no-such-file.fs"),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(f.error_span().map(|span| span.column), Some(1));
+    }
+
+    // Output
+
+    // An interpreter along with everything it prints.
+    fn capturing() -> (Forth, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+        let forth = Forth::new().with_output_callback(move |text| sink.borrow_mut().push_str(text));
+        (forth, output)
     }
 
     #[test]
-    fn drop_error() {
-        let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("drop"));
+    fn display_words_print() {
+        let (mut f, output) = capturing();
+        assert_eq!(
+            f.eval("1 -2 . . 3. d. 1.5e0 f. 65 emit space 2 spaces cr"),
+            Ok(())
+        );
+        assert_eq!(*output.borrow(), "-2 1 3 1.5 A   \n");
     }
 
     #[test]
-    fn swap() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 swap"), Ok(None));
-        assert_eq!(f.stack(), vec![2.0, 1.0]);
+    fn spaces_prints_any_count() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval("70 spaces -3 spaces 0 spaces"), Ok(()));
+        assert_eq!(*output.borrow(), " ".repeat(70));
     }
 
     #[test]
-    fn swap_with_three() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 swap"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 3.0, 2.0]);
+    fn show_stacks_print() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval("1 2 .s 2.5e0 f.s"), Ok(()));
+        assert_eq!(*output.borrow(), "<2> 1 2 <1> 2.5 ");
     }
 
     #[test]
-    fn swap_case_insensitive() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 SWAP 3 Swap 4 swap"), Ok(None));
-        assert_eq!(f.stack(), vec![2.0, 3.0, 4.0, 1.0]);
+    fn strings_print_exactly() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval(": hi .\" a  b 1\" ; hi s\" |c d|\" type"), Ok(()));
+        assert_eq!(*output.borrow(), "a  b 1|c d|");
     }
 
     #[test]
-    fn swap_error() {
-        let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 swap"));
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("swap"));
+    fn dot_paren_prints_while_compiling() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval(": foo .( compiling) .\" running\" ;"), Ok(()));
+        assert_eq!(*output.borrow(), "compiling");
+        assert_eq!(f.eval("foo"), Ok(()));
+        assert_eq!(*output.borrow(), "compilingrunning");
+    }
+
+    // A writer whose output the test can still read once it is boxed.
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn two_swap() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 4 2swap"), Ok(None));
-        assert_eq!(f.stack(), vec![3.0, 4.0, 1.0, 2.0]);
+    fn output_to_writer() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut f = Forth::new().with_output(Shared(output.clone()));
+        assert_eq!(f.eval("42 ."), Ok(()));
+        assert_eq!(*output.borrow(), b"42 ");
     }
 
     #[test]
-    fn two_swap_case_insensitive() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 4 2SWAP 2Swap 2swap"), Ok(None));
-        assert_eq!(f.stack(), vec![3.0, 4.0, 1.0, 2.0]);
+    fn output_is_flushed_after_errors() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let writer = io::BufWriter::new(Shared(output.clone()));
+        let mut f = Forth::new().with_output(writer);
+        assert_eq!(
+            f.eval("42 . nope"),
+            Err(ForthError::UnknownWord("nope".to_string()))
+        );
+        assert_eq!(*output.borrow(), b"42 ");
     }
 
     #[test]
-    fn two_swap_error() {
-        let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 2swap"));
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("2swap"));
+    fn evaluation_errors_outrank_flush_errors() {
+        struct Unflushable;
+
+        impl Write for Unflushable {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+        }
+
+        let mut f = Forth::new().with_output(Unflushable);
+        assert_eq!(
+            f.eval("1 ."),
+            Err(ForthError::OutputError("closed".to_string()))
+        );
+        assert_eq!(
+            f.eval("nope"),
+            Err(ForthError::UnknownWord("nope".to_string()))
+        );
     }
 
     #[test]
-    fn over() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 over"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 1.0]);
+    fn output_errors_are_reported() {
+        struct Closed;
+
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut f = Forth::new().with_output(Closed);
+        assert_eq!(f.eval("1 2"), Ok(()));
+        assert_eq!(
+            f.eval("."),
+            Err(ForthError::OutputError("closed".to_string()))
+        );
     }
 
     #[test]
-    fn over_with_three() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 over"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 3.0, 2.0]);
+    fn key_reads_characters() {
+        let mut f = Forth::new().with_input(io::Cursor::new("hé"));
+        assert_eq!(f.eval("key? key key key?"), Ok(()));
+        assert_eq!(f.stack(), [-1, 'h' as i64, 'é' as i64, 0]);
+        assert_eq!(f.eval("key"), Err(ForthError::EndOfInput));
     }
 
     #[test]
-    fn over_case_insensitive() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 OVER Over over"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 1.0, 2.0, 1.0]);
+    fn accept_reads_a_line() {
+        let (f, output) = capturing();
+        let mut f = f.with_input(io::Cursor::new("hello world\nhi\n"));
+        assert_eq!(f.eval("create buf 80 allot buf 5 accept"), Ok(()));
+        assert_eq!(f.stack(), [5]);
+        assert_eq!(f.eval("buf swap type buf 80 accept buf 80 accept"), Ok(()));
+        assert_eq!(f.stack(), [2, 0]);
+        assert_eq!(*output.borrow(), "hello");
     }
 
     #[test]
-    fn over_error() {
-        let mut f = Forth::new();
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("1 over"));
-        assert_eq!(Err(ForthError::StackUnderflow), f.eval("over"));
+    fn output_is_flushed_before_reading() {
+        // Answers with whatever had been written when the line was read.
+        struct Echo(Rc<RefCell<Vec<u8>>>);
+
+        impl Reader for Echo {
+            fn read_char(&mut self) -> io::Result<Option<char>> {
+                Ok(None)
+            }
+
+            fn char_ready(&mut self) -> io::Result<bool> {
+                Ok(false)
+            }
+
+            fn read_line(&mut self) -> io::Result<Option<String>> {
+                Ok(Some(String::from_utf8_lossy(&self.0.borrow()).into_owned()))
+            }
+        }
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let writer = io::BufWriter::new(Shared(output.clone()));
+        let reader = Echo(output.clone());
+        let mut f = Forth::new().with_output(writer).with_input(reader);
+        assert_eq!(
+            f.eval(".\" Name? \" create buf 20 allot buf 20 accept buf swap type"),
+            Ok(())
+        );
+        assert_eq!(*output.borrow(), b"Name? Name? ");
     }
 
     #[test]
-    fn two_over() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 4 2over"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
+    fn refill_reads_the_next_line() {
+        let mut f = Forth::new().with_input(io::Cursor::new("40 2 +\n"));
+        assert_eq!(f.eval("refill 1 2"), Ok(()));
+        assert_eq!(f.stack(), [-1, 42]);
+        assert_eq!(f.eval("refill"), Ok(()));
+        assert_eq!(f.stack(), [-1, 42, 0]);
     }
 
     #[test]
-    fn two_over_case_insensitive() {
+    fn refill_moves_to_the_next_line_of_the_input() {
+        let mut f = Forth::new().with_input(io::Cursor::new(""));
+        assert_eq!(f.eval("refill 1\n2 refill"), Ok(()));
+        assert_eq!(f.stack(), [-1, 2, 0]);
+    }
+
+    #[test]
+    fn source_and_to_in() {
+        let (mut f, output) = capturing();
+        assert_eq!(f.eval("source type"), Ok(()));
+        assert_eq!(*output.borrow(), "source type");
+        assert_eq!(f.eval("1 >in @ 2"), Ok(()));
+        assert_eq!(f.stack(), [1, 7, 2]);
+    }
+
+    #[test]
+    fn to_in_can_skip_input() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 3 4 2OVER 2Over 2over"), Ok(None));
+        assert_eq!(f.eval("1 source swap drop >in ! 2 3"), Ok(()));
+        assert_eq!(f.stack(), [1]);
         assert_eq!(
-            f.stack(),
-            vec![1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0, 1.0, 2.0]
+            f.eval(": skip source swap drop >in ! ; 4 skip 5\n6"),
+            Ok(())
         );
+        assert_eq!(f.stack(), [1, 4]);
     }
 
     #[test]
-    fn two_over_error() {
+    fn exchange_values_with_the_host() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2over"), Err(ForthError::StackUnderflow));
-        assert_eq!(f.eval("1 2 2over"), Err(ForthError::StackUnderflow));
-        assert_eq!(f.eval("1 2 3 2over"), Err(ForthError::StackUnderflow));
+        f.push(6);
+        f.push_double(1 << 70);
+        f.push_float(2.5);
+        assert_eq!(f.push_string("a b"), Ok(()));
+        assert_eq!(f.eval("s\" cd\" 2swap"), Ok(()));
+        assert_eq!(f.pop_string(), Ok("a b".to_string()));
+        assert_eq!(f.pop_string(), Ok("cd".to_string()));
+        assert_eq!(f.pop_float(), Ok(2.5));
+        assert_eq!(f.pop_double(), Ok(1 << 70));
+        assert_eq!(f.pop(), Ok(6));
+        assert_eq!(f.pop(), Err(ForthError::StackUnderflow));
+        assert_eq!(f.pop_float(), Err(ForthError::FloatStackUnderflow));
     }
 
-    // User-defined words
+    #[test]
+    fn host_words() {
+        let (mut f, output) = capturing();
+        f.register("Greet", |state| {
+            let name = state.pop_string()?;
+            state.print(format_args!("hello {}", name))
+        });
+        assert_eq!(f.eval("s\" forth\" greet"), Ok(()));
+        assert_eq!(*output.borrow(), "hello forth");
+        assert!(f.words().contains(&"greet".to_string()));
+    }
 
     #[test]
-    fn can_consist_of_built_in_words() {
+    fn host_words_keep_their_state() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": dup-twice dup dup ;"), Ok(None));
-        assert_eq!(f.eval("1 dup-twice"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0, 1.0]);
+        let mut count = 0;
+        f.register("count", move |state| {
+            count += 1;
+            state.push(count);
+            Ok(())
+        });
+        assert_eq!(f.eval(": twice count count ; twice count"), Ok(()));
+        assert_eq!(f.stack(), [1, 2, 3]);
     }
 
     #[test]
-    fn execute_in_the_right_order() {
+    fn host_words_shadow_builtins_but_not_definitions() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": countup 1 2 3 ;"), Ok(None));
-        assert_eq!(f.eval("countup"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 2.0, 3.0]);
+        f.register("dup", |state| {
+            state.push(0);
+            Ok(())
+        });
+        assert_eq!(f.eval("1 dup : dup 2 ; dup"), Ok(()));
+        assert_eq!(f.stack(), [1, 0, 2]);
     }
 
     #[test]
-    fn redefining_an_existing_word() {
+    fn host_word_errors_propagate() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": foo dup ;"), Ok(None));
-        assert_eq!(f.eval(": foo dup dup ;"), Ok(None));
-        assert_eq!(f.eval("1 foo"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0, 1.0]);
+        f.register("fail", |_| Err(ForthError::DivisionByZero));
+        assert_eq!(f.eval("1 fail 2"), Err(ForthError::DivisionByZero));
+        assert_eq!(f.stack(), [1]);
     }
 
     #[test]
-    fn redefining_an_existing_built_in_word() {
+    fn host_stack_effects_are_checked() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": swap dup ;"), Ok(None));
-        assert_eq!(f.eval("1 swap"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0]);
+        let calls = Rc::new(RefCell::new(0));
+        let counter = calls.clone();
+        let effect = StackEffect {
+            inputs: 2,
+            outputs: 1,
+        };
+        f.register_with_effect("add", effect, move |state| {
+            *counter.borrow_mut() += 1;
+            let (a, b) = state.pop2()?;
+            state.push(a + b);
+            Ok(())
+        });
+        f.register_with_effect("bad", effect, |state| {
+            state.push(0);
+            Ok(())
+        });
+        assert_eq!(f.eval("1 2 add"), Ok(()));
+        assert_eq!(f.stack(), [3]);
+        assert_eq!(f.eval("add"), Err(ForthError::StackUnderflow));
+        assert_eq!(*calls.borrow(), 1);
+        assert_eq!(
+            f.eval("4 bad"),
+            Err(ForthError::StackEffectMismatch("bad".to_string()))
+        );
+    }
+
+    // A chain of `n` definitions, each calling the one before.
+    fn nested_definitions(n: usize) -> String {
+        let mut source = ": w0 1 ;".to_string();
+        for i in 1..n {
+            source += &format!(" : w{} w{} ;", i, i - 1);
+        }
+        source
     }
 
     #[test]
-    fn user_defined_words_are_case_insensitive() {
+    fn call_depth_is_limited() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": foo dup ;"), Ok(None));
-        assert_eq!(f.eval("1 FOO Foo foo"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(f.eval(&nested_definitions(300)), Ok(()));
+        assert_eq!(f.eval("w254"), Ok(()));
+        assert_eq!(f.stack(), [1]);
+        assert_eq!(f.eval("w255"), Err(ForthError::CallDepthExceeded));
+        assert_eq!(f.stack(), [1]);
+
+        let mut f = Forth::new().with_limits(Limits {
+            call_depth: Some(10),
+            ..Limits::none()
+        });
+        assert_eq!(f.eval(&nested_definitions(10)), Ok(()));
+        assert_eq!(f.eval("w8"), Ok(()));
+        assert_eq!(f.eval("w9"), Err(ForthError::CallDepthExceeded));
     }
 
     #[test]
-    fn definitions_are_case_insensitive() {
+    fn does_nesting_is_limited() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": SWAP DUP Dup dup ;"), Ok(None));
-        assert_eq!(f.eval("1 swap"), Ok(None));
-        assert_eq!(f.stack(), vec![1.0, 1.0, 1.0, 1.0]);
+        let deep = |n| format!(": f {};", "does> ".repeat(n));
+        assert_eq!(f.eval(&deep(256)), Ok(()));
+        assert_eq!(f.eval(&deep(257)), Err(ForthError::CallDepthExceeded));
+        assert_eq!(f.eval(&deep(200000)), Err(ForthError::CallDepthExceeded));
     }
 
     #[test]
-    fn redefining_a_built_in_operator() {
+    fn recursive_includes_are_limited() {
+        let dir = source_files("recursive", &[("self.fs", "include self.fs")]);
         let mut f = Forth::new();
-        assert_eq!(f.eval(": + * ;"), Ok(None));
-        assert_eq!(f.eval("3 4 +"), Ok(None));
-        assert_eq!(f.stack(), vec![12.0]);
+        let mut error = f.include(dir.join("self.fs").to_str().unwrap());
+        while let Err(ForthError::Included { error: inner, .. }) = error {
+            error = Err(*inner);
+        }
+        assert_eq!(error, Err(ForthError::IncludeDepthExceeded));
     }
 
     #[test]
-    fn can_define_variable() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval(": foo 5 ;"), Ok(None));
-        assert_eq!(f.eval("foo"), Ok(None));
-        assert_eq!(f.stack(), vec![5.0]);
+    fn steps_are_limited() {
+        let mut f = Forth::new().with_limits(Limits {
+            steps: Some(100),
+            ..Limits::default()
+        });
+        assert_eq!(f.eval(": forever begin again ; 1 2"), Ok(()));
+        assert_eq!(f.eval("forever"), Err(ForthError::StepLimitExceeded));
+        // Each evaluation gets a fresh budget.
+        assert_eq!(f.eval("+"), Ok(()));
+        assert_eq!(f.stack(), [3]);
     }
 
     #[test]
-    fn can_use_different_words_with_the_same_name() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval(": foo 5 ;"), Ok(None));
-        assert_eq!(f.eval(": bar foo ;"), Ok(None));
-        assert_eq!(f.eval(": foo 6 ;"), Ok(None));
-        assert_eq!(f.eval("bar foo"), Ok(None));
-        assert_eq!(f.stack(), vec![5.0, 6.0]);
+    fn stack_depths_are_limited() {
+        let mut f = Forth::new().with_limits(Limits {
+            stack_depth: Some(3),
+            return_stack_depth: Some(2),
+            ..Limits::default()
+        });
+        assert_eq!(f.eval("1 2 3"), Ok(()));
+        assert_eq!(f.eval("4"), Err(ForthError::StackOverflow));
+        assert_eq!(f.stack(), [1, 2, 3]);
+        assert_eq!(f.eval("drop drop drop"), Ok(()));
+        assert_eq!(f.eval("1e 2e 3e 4e"), Err(ForthError::FloatStackOverflow));
+        assert_eq!(f.float_stack(), [1.0, 2.0, 3.0]);
+        assert_eq!(f.eval("fdrop fdrop fdrop"), Ok(()));
+        assert_eq!(
+            f.eval(": deep 1 >r 2 >r 3 >r rdrop rdrop rdrop ; deep"),
+            Err(ForthError::ReturnStackOverflow)
+        );
     }
 
     #[test]
-    fn can_define_word_that_uses_word_with_the_same_name() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval(": foo 10 ;"), Ok(None));
-        assert_eq!(f.eval(": foo foo 1 + ;"), Ok(None));
-        assert_eq!(f.eval("foo"), Ok(None));
-        assert_eq!(f.stack(), vec![11.0]);
+    fn stacks_are_usable_after_overflowing() {
+        let mut f = Forth::new().with_limits(Limits {
+            stack_depth: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(
+            f.eval(": f begin 1 again ; f"),
+            Err(ForthError::StackOverflow)
+        );
+        assert_eq!(f.stack().len(), 10);
+        assert_eq!(f.eval("drop 5"), Ok(()));
+        assert_eq!(f.stack()[9], 5);
     }
 
     #[test]
-    fn defining_a_number() {
+    fn allot_fails_when_memory_runs_out() {
         let mut f = Forth::new();
-        let result = f.eval(": 1 2 ;");
-        assert!(matches!(result, Err(ForthError::InvalidWord(_))));
+        assert_eq!(f.eval("1 allot here"), Ok(()));
+        assert_eq!(
+            f.eval("1125899906842624 allot"),
+            Err(ForthError::DataSpaceExhausted)
+        );
+        assert_eq!(f.eval("here"), Ok(()));
+        assert_eq!(f.stack(), [1, 1]);
     }
 
     #[test]
-    fn malformed_word_definition() {
-        let mut f = Forth::new();
-        assert_eq!(Err(ForthError::Unterminated), f.eval(":"));
-        assert_eq!(Err(ForthError::Unterminated), f.eval(": foo"));
-        assert_eq!(Err(ForthError::Unterminated), f.eval(": foo 1"));
+    fn data_space_is_limited() {
+        let mut f = Forth::new().with_limits(Limits {
+            data_space: Some(64),
+            ..Limits::default()
+        });
+        assert_eq!(f.eval("here 64 allot here swap -"), Ok(()));
+        assert_eq!(f.stack(), [64]);
+        assert_eq!(f.eval("1 allot"), Err(ForthError::DataSpaceExhausted));
+        assert_eq!(
+            f.eval("1000000000000 allot"),
+            Err(ForthError::DataSpaceExhausted)
+        );
+        assert_eq!(f.eval("-64 allot s\" x\""), Ok(()));
     }
 
     #[test]
-    fn calling_non_existing_word() {
-        let mut f = Forth::new();
+    fn strings_respect_the_data_space_limit() {
+        let mut f = Forth::new().with_limits(Limits {
+            data_space: Some(4),
+            ..Limits::default()
+        });
         assert_eq!(
-            Err(ForthError::UnknownWord("foo".to_string())),
-            f.eval("1 foo")
+            f.eval("s\" abcdefgh\""),
+            Err(ForthError::DataSpaceExhausted)
+        );
+        assert_eq!(f.eval("c\" abcd\""), Err(ForthError::DataSpaceExhausted));
+        assert_eq!(
+            f.eval(": foo s\" abcdefgh\" ;"),
+            Err(ForthError::DataSpaceExhausted)
+        );
+        assert_eq!(
+            f.push_string("abcdefgh"),
+            Err(ForthError::DataSpaceExhausted)
         );
+        // Nothing was kept, so the interpreter still works.
+        assert_eq!(f.eval("1 2 + here s\" abcd\" swap drop"), Ok(()));
+        assert_eq!(f.stack(), [3, 0, 4]);
     }
 
     #[test]
-    fn multiple_definitions() {
+    fn interrupt_from_another_thread() {
         let mut f = Forth::new();
-        assert_eq!(f.eval(": one 1 ; : two 2 ; one two +"), Ok(None));
-        assert_eq!(f.stack(), vec![3.0]);
+        let interrupt = f.interrupt_handle();
+        assert_eq!(
+            f.eval(": spin begin again ; : outer 1 >r spin ; 1 2"),
+            Ok(())
+        );
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(f.eval("outer"), Err(ForthError::Interrupted));
+        interrupter.join().unwrap();
+        assert_eq!(f.return_stack(), []);
+        assert!(f.state.tokens.is_empty() && f.state.inputs.is_empty());
+        assert_eq!(f.eval("+"), Ok(()));
+        assert_eq!(f.stack(), [3]);
     }
 
     #[test]
-    fn definitions_after_ops() {
+    fn interrupts_do_not_outlive_evaluation() {
         let mut f = Forth::new();
-        assert_eq!(f.eval("1 2 + : addone 1 + ; addone"), Ok(None));
-        assert_eq!(f.stack(), vec![4.0]);
+        let interrupt = f.interrupt_handle();
+        interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(f.eval("1 2 +"), Ok(()));
+        assert_eq!(f.stack(), [3]);
+        // Nor do they outlive an evaluation which finished on its own.
+        assert_eq!(f.eval(": late 1 ;"), Ok(()));
+        interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(f.eval("late"), Ok(()));
+        assert_eq!(f.stack(), [3, 1]);
     }
 
     #[test]
-    fn redefine_an_existing_word_with_another_existing_word() {
-        let mut f = Forth::new();
-        assert_eq!(f.eval(": foo 5 ;"), Ok(None));
-        assert_eq!(f.eval(": bar foo ;"), Ok(None));
-        assert_eq!(f.eval(": foo 6 ;"), Ok(None));
-        assert_eq!(f.eval(": bar foo ;"), Ok(None));
-        assert_eq!(f.eval("bar foo"), Ok(None));
-        assert_eq!(f.stack(), vec![6.0, 6.0]);
+    fn evaluation_times_out() {
+        let mut f = Forth::new().with_limits(Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert_eq!(f.eval(": spin begin again ;"), Ok(()));
+        assert_eq!(f.eval("spin"), Err(ForthError::TimedOut));
+        // Each evaluation gets its own time.
+        assert_eq!(f.eval("1 2 +"), Ok(()));
+        assert_eq!(f.stack(), [3]);
+    }
+
+    #[test]
+    fn huge_spaces_count_times_out() {
+        let mut f = Forth::new().with_output(io::sink()).with_limits(Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert_eq!(f.eval("-1 1 rshift spaces"), Err(ForthError::TimedOut));
+        let mut f = Forth::new().with_output(io::sink()).with_limits(Limits {
+            steps: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(
+            f.eval("-1 1 rshift spaces"),
+            Err(ForthError::StepLimitExceeded)
+        );
     }
 }
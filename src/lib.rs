@@ -0,0 +1,38 @@
+//! A Forth interpreter which can be embedded in other programs.
+//!
+//! Create a [`Forth`], feed it source with [`Forth::eval`], and exchange
+//! values with it through its stacks:
+//!
+//! ```
+//! use forth::Forth;
+//!
+//! let mut forth = Forth::new().with_output(Vec::new());
+//! forth.eval(": square dup * ;").unwrap();
+//! forth.push(7);
+//! forth.eval("square").unwrap();
+//! assert_eq!(forth.pop(), Ok(49));
+//! ```
+//!
+//! Rust functions can be added as words with [`Forth::register`]:
+//!
+//! ```
+//! use forth::{Forth, StackEffect};
+//!
+//! let mut forth = Forth::new();
+//! let effect = StackEffect { inputs: 2, outputs: 1 };
+//! forth.register_with_effect("hypot", effect, |state| {
+//!     let (b, a) = (state.pop()? as f64, state.pop()? as f64);
+//!     state.push(a.hypot(b) as i64);
+//!     Ok(())
+//! });
+//! forth.eval("3 4 hypot").unwrap();
+//! assert_eq!(forth.stack(), [5]);
+//! ```
+//!
+//! Output goes to standard output and KEY, ACCEPT and REFILL read standard
+//! input unless [`Forth::with_output`] or [`Forth::with_input`] say
+//! otherwise.
+
+mod forth;
+
+pub use crate::forth::{Forth, ForthError, Limits, Reader, Span, StackEffect, State};
@@ -1,44 +1,278 @@
-#![feature(iter_intersperse)]
-
+use std::env;
+use std::fs;
 use std::io::{self, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::sync::atomic::Ordering;
 
-mod forth;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 
-use forth::{Forth, ForthError};
+use forth::{Forth, ForthError, Span};
 
-fn main() {
-    let mut forth = Forth::new();
+// How the REPL reports on each line it evaluates.
+#[derive(Default)]
+struct ReplOptions {
+    // Follow "ok" with the data stack depth, as in `ok 3`.
+    show_depth: bool,
+}
 
-    loop {
-        let mut input = String::new();
+// Completes words against the dictionary as it was when the line started.
+#[derive(Default)]
+struct WordCompleter {
+    words: Vec<String>,
+}
+
+impl Completer for WordCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |index| index + 1);
+        let prefix = line[start..pos].to_lowercase();
+        let candidates = self
+            .words
+            .iter()
+            .filter(|word| word.starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for WordCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for WordCompleter {}
+
+impl Validator for WordCompleter {}
+
+impl Helper for WordCompleter {}
+
+type LineEditor = Editor<WordCompleter, DefaultHistory>;
+
+const USAGE: &str = "Usage: forth [--depth] [-i] [-e CODE]... [FILE [ARG]...]
+
+  -e CODE   evaluate CODE, after any earlier -e options
+  -i        start the REPL after running CODE and FILE
+  --depth   show the stack depth after \"ok\" in the REPL
+  FILE      run a Forth source file, passing it the remaining ARGs";
+
+// Forth source to run before, or instead of, the REPL.
+enum Source {
+    Code(String),
+    File(String),
+}
 
-        print!("{}", forth.prompt());
-        io::stdout().flush().unwrap();
+struct Options {
+    repl: ReplOptions,
+    // Enter the REPL even after running sources.
+    interactive: bool,
+    sources: Vec<Source>,
+    // The arguments made available to ARGC and ARG.
+    args: Vec<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        repl: ReplOptions::default(),
+        interactive: false,
+        sources: Vec::new(),
+        args: vec![args.next().unwrap_or_else(|| "forth".to_string())],
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--depth" => options.repl.show_depth = true,
+            "-i" => options.interactive = true,
+            "-e" => match args.next() {
+                Some(code) => options.sources.push(Source::Code(code)),
+                None => return Err("-e needs an argument".to_string()),
+            },
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            option if option.starts_with('-') && option != "-" => {
+                return Err(format!("unknown option: {}\n\n{}", option, USAGE));
+            }
+            _ => {
+                // The script sees itself and everything after it.
+                options.args = iter::once(arg.clone()).chain(args).collect();
+                options.sources.push(Source::File(arg));
+                break;
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args()) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut forth = Forth::new();
+    if let Err(msg) = forth.set_args(&options.args) {
+        eprintln!("{}", msg);
+        return ExitCode::FAILURE;
+    }
+
+    // Ctrl-C stops whatever is being evaluated. While a line is being
+    // edited it cancels the line instead. It cannot stop KEY, ACCEPT or
+    // REFILL while they wait for input, so a second Ctrl-C before the
+    // first has been noticed exits, as it would without this handler.
+    let interrupt = forth.interrupt_handle();
+    let handler = move || {
+        if interrupt.swap(true, Ordering::Relaxed) {
+            process::exit(130);
+        }
+    };
+    if let Err(msg) = ctrlc::set_handler(handler) {
+        eprintln!("Could not handle Ctrl-C: {}", msg);
+    }
 
-        match io::stdin().read_line(&mut input) {
+    for source in &options.sources {
+        let result = match source {
+            Source::Code(code) => forth.eval(code).and_then(|()| {
+                if forth.is_compiling() {
+                    Err(ForthError::Unterminated)
+                } else {
+                    Ok(())
+                }
+            }),
+            Source::File(path) => forth.include(path),
+        };
+        match result {
+            Ok(()) => {}
+            Err(ForthError::UserQuit) => return ExitCode::SUCCESS,
             Err(msg) => {
-                println!("Error: {}", msg);
+                io::stdout().flush().unwrap();
+                match (source, forth.error_span()) {
+                    (Source::Code(code), Some(span)) => {
+                        eprintln!("-e:{}:{}: {}", span.line, span.column, msg);
+                        show_span(&mut io::stderr(), code, span);
+                    }
+                    (Source::Code(_), None) => eprintln!("-e: {}", msg),
+                    // Errors from files name the file and line themselves.
+                    (Source::File(_), _) => eprintln!("{}", msg),
+                }
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if options.sources.is_empty() || options.interactive {
+        repl(&mut forth, &options.repl);
+    }
+    ExitCode::SUCCESS
+}
+
+fn repl(forth: &mut Forth, options: &ReplOptions) {
+    let mut editor = match LineEditor::new() {
+        Ok(editor) => editor,
+        Err(msg) => {
+            eprintln!("Error: {}", msg);
+            return;
+        }
+    };
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history the first time the REPL is run.
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        editor.set_helper(Some(WordCompleter {
+            words: forth.words(),
+        }));
+        match editor.readline(&forth.prompt()) {
+            // Ctrl-C abandons the line being typed.
+            Err(ReadlineError::Interrupted) => {
+                continue;
+            }
+            // Ctrl-D leaves, just like `bye`.
+            Err(ReadlineError::Eof) => {
                 break;
             }
-            Ok(0) => {
+            Err(msg) => {
+                println!("Error: {}", msg);
                 break;
             }
-            Ok(_) => match forth.eval(&input) {
-                Ok(result) => match result {
-                    Some(value) => {
-                        println!("{} Ok", value);
+            Ok(input) => {
+                let _ = editor.add_history_entry(input.as_str());
+                match forth.eval(&input) {
+                    Ok(()) => {
+                        println!(" {}", status(forth, options));
                     }
-                    None => {
-                        println!(" Ok");
+                    Err(ForthError::UserQuit) => {
+                        break;
+                    }
+                    Err(msg) => {
+                        println!("? Error: {}", msg);
+                        if let Some(span) = forth.error_span() {
+                            show_span(&mut io::stdout(), &input, span);
+                        }
                     }
-                },
-                Err(ForthError::UserQuit) => {
-                    break;
-                }
-                Err(msg) => {
-                    println!("? Error: {}", msg);
                 }
-            },
+            }
         }
     }
+
+    if let Some(path) = &history {
+        if let Err(msg) = save_history(&mut editor, path) {
+            eprintln!("Could not save history: {}", msg);
+        }
+    }
+}
+
+// History is kept in `forth/history` under the user's config directory.
+fn history_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("forth").join("history"))
+}
+
+fn save_history(editor: &mut LineEditor, path: &Path) -> Result<(), ReadlineError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    editor.save_history(path)
+}
+
+// The acknowledgement printed after a line is evaluated successfully.
+fn status(forth: &Forth, options: &ReplOptions) -> String {
+    if forth.is_compiling() {
+        "compiled".to_string()
+    } else if options.show_depth && forth.depth() > 0 {
+        format!("ok {}", forth.depth())
+    } else {
+        "ok".to_string()
+    }
+}
+
+// Underline the part of the input an error refers to.
+fn show_span(out: &mut impl Write, input: &str, span: Span) {
+    if let Some(line) = input.lines().nth(span.line - 1) {
+        let _ = writeln!(out, "  {}", line);
+        let _ = writeln!(
+            out,
+            "  {}{}",
+            " ".repeat(span.column - 1),
+            "^".repeat(span.len.max(1))
+        );
+    }
 }